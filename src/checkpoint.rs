@@ -0,0 +1,187 @@
+// The safetensors files of a model directory.
+// Small models ship a single `model.safetensors`; larger ones are split into
+// `model-0000x-of-0000y.safetensors` shards plus a `model.safetensors.index.json` that maps
+// every tensor name to the shard holding it.
+use crate::dtype::WeightType;
+use crate::error::LoadError;
+use crate::tensor::Tensor;
+use memmap2::Mmap;
+use safetensors::tensor::{Metadata, TensorView};
+use safetensors::SafeTensors;
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+
+const SINGLE_FILE: &str = "model.safetensors";
+const INDEX_FILE: &str = "model.safetensors.index.json";
+
+#[derive(serde::Deserialize)]
+struct SafetensorsIndex {
+    weight_map: HashMap<String, String>,
+}
+
+struct Shard {
+    map: Arc<Mmap>,
+    data_start: usize, // the tensor data follows the 8-byte length and the JSON header
+    metadata: Metadata,
+}
+
+pub struct Checkpoint {
+    shards: Vec<Shard>,
+    weight_map: HashMap<String, usize>, // tensor name -> index into shards
+}
+
+impl Checkpoint {
+    // Open model.safetensors.index.json and its shards when present, model.safetensors otherwise
+    pub fn from_model_dir(model_dir: &Path) -> Result<Self, LoadError> {
+        let index_path = model_dir.join(INDEX_FILE);
+        if !index_path.exists() {
+            let shard = Shard::open(&model_dir.join(SINGLE_FILE))?;
+            let weight_map = shard
+                .metadata
+                .tensors()
+                .into_keys()
+                .map(|name| (name, 0))
+                .collect();
+            return Ok(Checkpoint {
+                shards: vec![shard],
+                weight_map,
+            });
+        }
+
+        let index_file = File::open(&index_path).map_err(|source| LoadError::Io {
+            path: index_path.clone(),
+            source,
+        })?;
+        let index: SafetensorsIndex =
+            serde_json::from_reader(index_file).map_err(|source| LoadError::Json {
+                path: index_path,
+                source,
+            })?;
+        // Shards are numbered in file name order, independent of the order of the index entries
+        let mut files = index.weight_map.values().cloned().collect::<Vec<_>>();
+        files.sort();
+        files.dedup();
+        let shards = files
+            .iter()
+            .map(|file| Shard::open(&model_dir.join(file)))
+            .collect::<Result<_, _>>()?;
+        let weight_map = index
+            .weight_map
+            .into_iter()
+            .map(|(name, file)| (name, files.binary_search(&file).unwrap()))
+            .collect();
+        Ok(Checkpoint { shards, weight_map })
+    }
+
+    pub fn tensor(&self, name: &str) -> Option<TensorView<'_>> {
+        let shard = &self.shards[*self.weight_map.get(name)?];
+        shard.tensor(name)
+    }
+
+    // The tensor borrowed from the mapped file without a copy, if it is stored as T and
+    // suitably aligned
+    pub fn borrow_tensor<T: WeightType>(&self, name: &str) -> Option<Tensor<T>> {
+        let shard = &self.shards[*self.weight_map.get(name)?];
+        let info = shard.metadata.info(name)?;
+        if info.dtype != T::DTYPE || cfg!(target_endian = "big") {
+            return None;
+        }
+        // Safety: the bytes of a tensor with dtype T::DTYPE are valid T values
+        unsafe {
+            Tensor::from_mapping(
+                shard.map.clone(),
+                shard.data_start + info.data_offsets.0,
+                &info.shape,
+            )
+        }
+    }
+}
+
+impl Shard {
+    fn open(path: &Path) -> Result<Self, LoadError> {
+        let io_error = |source| LoadError::Io {
+            path: path.to_path_buf(),
+            source,
+        };
+        let file = File::open(path).map_err(io_error)?;
+        // Safety: the checkpoint must not be modified while the model is loaded
+        let map = unsafe { Mmap::map(&file) }.map_err(io_error)?;
+        let (header_len, metadata) =
+            SafeTensors::read_metadata(&map).map_err(|source| LoadError::Safetensors {
+                path: path.to_path_buf(),
+                source,
+            })?;
+        Ok(Shard {
+            map: Arc::new(map),
+            data_start: 8 + header_len,
+            metadata,
+        })
+    }
+
+    fn tensor(&self, name: &str) -> Option<TensorView<'_>> {
+        let info = self.metadata.info(name)?;
+        let (start, end) = info.data_offsets;
+        let data = &self.map[self.data_start..][start..end];
+        Some(TensorView::new(info.dtype, info.shape.clone(), data).unwrap())
+    }
+}
+
+#[test]
+fn test_sharded_checkpoint() {
+    use std::path::PathBuf;
+    let project_dir = env!("CARGO_MANIFEST_DIR");
+    let model_dir = PathBuf::from(project_dir).join("models").join("story");
+    let single = Checkpoint::from_model_dir(&model_dir).unwrap();
+
+    // 将 story 模型拆成两个分片并写出 index
+    let sharded_dir = std::env::temp_dir().join(format!("sharded-{}", std::process::id()));
+    std::fs::create_dir_all(&sharded_dir).unwrap();
+    let mut names = single.weight_map.keys().cloned().collect::<Vec<_>>();
+    names.sort();
+    let mut weight_map = serde_json::Map::new();
+    let (first, second) = names.split_at(names.len() / 2);
+    for (i, names) in [first, second].into_iter().enumerate() {
+        let file = format!("model-{:05}-of-00002.safetensors", i + 1);
+        let tensors = names
+            .iter()
+            .map(|name| (name, single.tensor(name).unwrap()));
+        safetensors::serialize_to_file(tensors, &None, &sharded_dir.join(&file)).unwrap();
+        for name in names {
+            weight_map.insert(name.clone(), file.clone().into());
+        }
+    }
+    let index = serde_json::json!({ "metadata": {}, "weight_map": weight_map });
+    std::fs::write(sharded_dir.join(INDEX_FILE), index.to_string()).unwrap();
+
+    let sharded = Checkpoint::from_model_dir(&sharded_dir).unwrap();
+    std::fs::remove_dir_all(&sharded_dir).unwrap();
+    assert_eq!(sharded.shards.len(), 2);
+    for name in &names {
+        let (a, b) = (single.tensor(name).unwrap(), sharded.tensor(name).unwrap());
+        assert_eq!(a.dtype(), b.dtype());
+        assert_eq!(a.shape(), b.shape());
+        assert_eq!(a.data(), b.data());
+    }
+    assert!(sharded.tensor("missing.weight").is_none());
+}
+
+#[test]
+fn test_borrow_tensor() {
+    use std::path::PathBuf;
+    let project_dir = env!("CARGO_MANIFEST_DIR");
+    let model_dir = PathBuf::from(project_dir).join("models").join("story");
+    let checkpoint = Checkpoint::from_model_dir(&model_dir).unwrap();
+
+    let name = "model.layers.0.self_attn.q_proj.weight";
+    let view = checkpoint.tensor(name).unwrap();
+    let t = checkpoint.borrow_tensor::<f32>(name).unwrap();
+    assert!(t.is_mapped());
+    assert_eq!(t.shape(), view.shape());
+    // 借用的数据就是文件中的字节
+    assert_eq!(t.data().as_ptr() as *const u8, view.data().as_ptr());
+    // 精度不同时需要转换，不能借用
+    assert!(checkpoint.borrow_tensor::<half::f16>(name).is_none());
+    assert!(checkpoint.borrow_tensor::<f32>("missing.weight").is_none());
+}
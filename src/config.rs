@@ -1,8 +1,15 @@
-use serde;
+use crate::error::LoadError;
+use serde::{Deserialize, Deserializer};
+use std::fs::File;
+use std::path::Path;
+
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 pub(crate) struct LlamaConfigJson {
-    pub bos_token_id: u32,
-    pub eos_token_id: u32,
+    #[serde(default)]
+    pub bos_token_id: Option<u32>,
+    // 可以是单个 id 或 id 列表；缺省时取 generation_config.json 中的值
+    #[serde(default, deserialize_with = "deserialize_token_ids")]
+    pub eos_token_id: Vec<u32>,
     pub hidden_size: usize,
     pub intermediate_size: usize,
     pub max_position_embeddings: usize,
@@ -14,11 +21,67 @@ pub(crate) struct LlamaConfigJson {
     pub rms_norm_eps: f32,
     #[serde(default = "default_rope_theta")]
     pub rope_theta: f32,
+    // 只用来选择权重在内存中的精度，能否加载由 checkpoint 中各 tensor 的 dtype 决定
+    #[serde(default)]
     pub torch_dtype: String,
     #[serde(default = "default_tie_word_embeddings")]
     pub tie_word_embeddings: bool,
 }
 
+// The token ids of generation_config.json, used when config.json leaves them out
+#[derive(serde::Deserialize, Debug)]
+struct GenerationConfigJson {
+    #[serde(default)]
+    bos_token_id: Option<u32>,
+    #[serde(default, deserialize_with = "deserialize_token_ids")]
+    eos_token_id: Vec<u32>,
+}
+
+impl LlamaConfigJson {
+    pub fn from_model_dir(model_dir: &Path) -> Result<Self, LoadError> {
+        let mut config: Self = read_json(&model_dir.join("config.json"))?;
+        if config.bos_token_id.is_none() || config.eos_token_id.is_empty() {
+            let path = model_dir.join("generation_config.json");
+            if path.exists() {
+                let generation: GenerationConfigJson = read_json(&path)?;
+                config.bos_token_id = config.bos_token_id.or(generation.bos_token_id);
+                if config.eos_token_id.is_empty() {
+                    config.eos_token_id = generation.eos_token_id;
+                }
+            }
+        }
+        Ok(config)
+    }
+}
+
+pub(crate) fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T, LoadError> {
+    let file = File::open(path).map_err(|source| LoadError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    serde_json::from_reader(file).map_err(|source| LoadError::Json {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+// A token id field holding a single id, a list of ids or null
+pub(crate) fn deserialize_token_ids<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<u32>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum TokenIds {
+        Single(u32),
+        Multiple(Vec<u32>),
+    }
+    Ok(match Option::<TokenIds>::deserialize(deserializer)? {
+        None => vec![],
+        Some(TokenIds::Single(id)) => vec![id],
+        Some(TokenIds::Multiple(ids)) => ids,
+    })
+}
+
 #[inline(always)]
 const fn default_rms_norm_eps() -> f32 {
     1e-5
@@ -33,3 +96,48 @@ const fn default_rope_theta() -> f32 {
 const fn default_tie_word_embeddings() -> bool {
     false
 }
+
+#[test]
+fn test_token_ids() {
+    use std::path::PathBuf;
+    let project_dir = env!("CARGO_MANIFEST_DIR");
+    let model_dir = PathBuf::from(project_dir).join("models").join("story");
+    let config = LlamaConfigJson::from_model_dir(&model_dir).unwrap();
+    assert_eq!(config.bos_token_id, Some(1));
+    assert_eq!(config.eos_token_id, [2]);
+
+    let mut json: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(model_dir.join("config.json")).unwrap())
+            .unwrap();
+    let test_dir = std::env::temp_dir().join(format!("token-ids-{}", std::process::id()));
+    std::fs::create_dir_all(&test_dir).unwrap();
+    let mut load = |eos: Option<serde_json::Value>, generation: Option<&str>| {
+        match eos {
+            Some(eos) => json["eos_token_id"] = eos,
+            None => _ = json.as_object_mut().unwrap().remove("eos_token_id"),
+        }
+        json.as_object_mut().unwrap().remove("bos_token_id");
+        std::fs::write(test_dir.join("config.json"), json.to_string()).unwrap();
+        let generation_path = test_dir.join("generation_config.json");
+        match generation {
+            Some(generation) => std::fs::write(generation_path, generation).unwrap(),
+            None => _ = std::fs::remove_file(generation_path),
+        }
+        LlamaConfigJson::from_model_dir(&test_dir).unwrap()
+    };
+
+    // 列表形式的 eos_token_id
+    let config = load(Some(serde_json::json!([2, 7])), None);
+    assert_eq!(config.eos_token_id, [2, 7]);
+    assert_eq!(config.bos_token_id, None);
+    // 缺省时使用 generation_config.json
+    let config = load(None, Some(r#"{"bos_token_id": 1, "eos_token_id": [3, 4]}"#));
+    assert_eq!(config.eos_token_id, [3, 4]);
+    assert_eq!(config.bos_token_id, Some(1));
+    // config.json 中的值优先
+    let config = load(Some(serde_json::json!(5)), Some(r#"{"eos_token_id": 6}"#));
+    assert_eq!(config.eos_token_id, [5]);
+    let config = load(Some(serde_json::Value::Null), None);
+    assert!(config.eos_token_id.is_empty());
+    std::fs::remove_dir_all(&test_dir).unwrap();
+}
@@ -0,0 +1,169 @@
+// Element types the model weights can be stored in.
+// Activations, the KV cache and all accumulation stay in f32; 16-bit weights are widened
+// to f32 a small chunk at a time inside the kernels, so they only cost half the memory
+// (and half the bandwidth) of f32 weights.
+use crate::simd;
+use half::slice::HalfFloatSliceExt;
+use half::{bf16, f16};
+use safetensors::Dtype;
+use std::borrow::Cow;
+
+// Number of weights widened per step by the default kernels
+const CHUNK: usize = 64;
+
+pub trait WeightType: Copy + Default + Send + Sync + 'static {
+    // The safetensors dtype whose bytes can be used as Self without conversion
+    const DTYPE: Dtype;
+
+    fn from_f32(x: f32) -> Self;
+
+    fn to_f32(self) -> f32;
+
+    // dst[i] = src[i] as f32
+    fn to_f32_slice(src: &[Self], dst: &mut [f32]) {
+        for (d, s) in dst.iter_mut().zip(src) {
+            *d = s.to_f32();
+        }
+    }
+
+    // The slice widened to f32, borrowed when it already is f32
+    fn as_f32(src: &[Self]) -> Cow<'_, [f32]> {
+        let mut dst = vec![0.; src.len()];
+        Self::to_f32_slice(src, &mut dst);
+        Cow::Owned(dst)
+    }
+
+    // sum(x[i] * w[i])
+    fn dot(x: &[f32], w: &[Self]) -> f32 {
+        assert_eq!(x.len(), w.len());
+        let mut buf = [0.; CHUNK];
+        let mut sum = 0.;
+        for (x, w) in x.chunks(CHUNK).zip(w.chunks(CHUNK)) {
+            let buf = &mut buf[..w.len()];
+            Self::to_f32_slice(w, buf);
+            sum += simd::dot(x, buf);
+        }
+        sum
+    }
+
+    // [dot(x, w[0]), .., dot(x, w[3])]
+    fn dot4(x: &[f32], w: [&[Self]; 4]) -> [f32; 4] {
+        assert!(w.iter().all(|w| w.len() == x.len()));
+        let mut bufs = [[0.; CHUNK]; 4];
+        let mut sums = [0.; 4];
+        for start in (0..x.len()).step_by(CHUNK) {
+            let len = CHUNK.min(x.len() - start);
+            for (buf, w) in bufs.iter_mut().zip(w) {
+                Self::to_f32_slice(&w[start..][..len], &mut buf[..len]);
+            }
+            let rows = [
+                &bufs[0][..len],
+                &bufs[1][..len],
+                &bufs[2][..len],
+                &bufs[3][..len],
+            ];
+            let partial = simd::dot4(&x[start..][..len], rows);
+            sums.iter_mut().zip(partial).for_each(|(s, p)| *s += p);
+        }
+        sums
+    }
+}
+
+impl WeightType for f32 {
+    const DTYPE: Dtype = Dtype::F32;
+
+    #[inline]
+    fn from_f32(x: f32) -> Self {
+        x
+    }
+
+    #[inline]
+    fn to_f32(self) -> f32 {
+        self
+    }
+
+    fn to_f32_slice(src: &[Self], dst: &mut [f32]) {
+        dst.copy_from_slice(src);
+    }
+
+    fn as_f32(src: &[Self]) -> Cow<'_, [f32]> {
+        Cow::Borrowed(src)
+    }
+
+    fn dot(x: &[f32], w: &[Self]) -> f32 {
+        simd::dot(x, w)
+    }
+
+    fn dot4(x: &[f32], w: [&[Self]; 4]) -> [f32; 4] {
+        simd::dot4(x, w)
+    }
+}
+
+impl WeightType for f16 {
+    const DTYPE: Dtype = Dtype::F16;
+
+    #[inline]
+    fn from_f32(x: f32) -> Self {
+        f16::from_f32(x)
+    }
+
+    #[inline]
+    fn to_f32(self) -> f32 {
+        f16::to_f32(self)
+    }
+
+    // Uses F16C / NEON fp16 conversions when the CPU has them
+    fn to_f32_slice(src: &[Self], dst: &mut [f32]) {
+        src.convert_to_f32_slice(dst);
+    }
+}
+
+impl WeightType for bf16 {
+    const DTYPE: Dtype = Dtype::BF16;
+
+    #[inline]
+    fn from_f32(x: f32) -> Self {
+        bf16::from_f32(x)
+    }
+
+    #[inline]
+    fn to_f32(self) -> f32 {
+        bf16::to_f32(self)
+    }
+
+    fn to_f32_slice(src: &[Self], dst: &mut [f32]) {
+        src.convert_to_f32_slice(dst);
+    }
+}
+
+#[test]
+fn test_half_dot() {
+    let x = (0..150)
+        .map(|i| (i % 9) as f32 * 0.5 - 2.0)
+        .collect::<Vec<_>>();
+    let rows = [1, 2, 3, 4].map(|s| {
+        (0..150)
+            .map(|i| ((i * s) % 7) as f32 * 0.25 - 0.75)
+            .collect::<Vec<_>>()
+    });
+    let expected = rows.each_ref().map(|w| simd::scalar::dot(&x, w));
+
+    let rows_f16 = rows
+        .each_ref()
+        .map(|w| w.iter().map(|&v| f16::from_f32(v)).collect::<Vec<_>>());
+    assert_eq!(f16::dot(&x, &rows_f16[0]), expected[0]);
+    let w = [&rows_f16[0][..], &rows_f16[1], &rows_f16[2], &rows_f16[3]];
+    assert_eq!(f16::dot4(&x, w), expected);
+
+    let rows_bf16 = rows
+        .each_ref()
+        .map(|w| w.iter().map(|&v| bf16::from_f32(v)).collect::<Vec<_>>());
+    assert_eq!(bf16::dot(&x, &rows_bf16[1]), expected[1]);
+    let w = [
+        &rows_bf16[0][..],
+        &rows_bf16[1],
+        &rows_bf16[2],
+        &rows_bf16[3],
+    ];
+    assert_eq!(bf16::dot4(&x, w), expected);
+}
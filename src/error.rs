@@ -0,0 +1,110 @@
+// Errors reported while loading a model directory
+use crate::quant::QuantType;
+use safetensors::{Dtype, SafeTensorError};
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+// A tensor that is missing or whose shape disagrees with config.json
+pub struct ShapeMismatch {
+    pub name: String,
+    pub expected: Vec<usize>,
+    pub actual: Option<Vec<usize>>, // None when the checkpoint has no such tensor
+}
+
+impl fmt::Display for ShapeMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.actual {
+            Some(actual) => write!(
+                f,
+                "{}: expected {:?}, found {:?}",
+                self.name, self.expected, actual
+            ),
+            None => write!(f, "{}: expected {:?}, not found", self.name, self.expected),
+        }
+    }
+}
+
+pub enum LoadError {
+    Io {
+        path: PathBuf,
+        source: io::Error,
+    },
+    Json {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    // The file is not a valid safetensors file
+    Safetensors {
+        path: PathBuf,
+        source: SafeTensorError,
+    },
+    // The tensor is stored in a dtype that cannot be converted to the weight type
+    DtypeMismatch {
+        name: String,
+        dtype: Dtype,
+    },
+    // Every tensor that is missing or whose shape disagrees with config.json
+    ShapeMismatch(Vec<ShapeMismatch>),
+    // The rows of the tensor cannot be split into blocks of the requested quantization
+    UnsupportedQuantization {
+        name: String,
+        quant: QuantType,
+        cols: usize,
+    },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io { path, source } => {
+                write!(f, "Failed to read {}: {}", path.display(), source)
+            }
+            LoadError::Json { path, source } => {
+                write!(f, "Failed to parse {}: {}", path.display(), source)
+            }
+            LoadError::Safetensors { path, source } => {
+                write!(f, "Invalid safetensors file {}: {:?}", path.display(), source)
+            }
+            LoadError::DtypeMismatch { name, dtype } => write!(
+                f,
+                "Tensor {} has unsupported dtype {:?}, only F32, F16 and BF16 checkpoints can be loaded",
+                name, dtype
+            ),
+            LoadError::ShapeMismatch(mismatches) => {
+                write!(
+                    f,
+                    "{} tensors are missing or do not match the shapes in config.json:",
+                    mismatches.len()
+                )?;
+                for mismatch in mismatches {
+                    write!(f, "\n  {}", mismatch)?;
+                }
+                Ok(())
+            }
+            LoadError::UnsupportedQuantization { name, quant, cols } => write!(
+                f,
+                "Tensor {} cannot be quantized to {:?}: {} columns is not a multiple of the block size",
+                name, quant, cols
+            ),
+        }
+    }
+}
+
+// Show the message rather than the variant, so `main` returning an error stays readable
+impl fmt::Debug for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl std::error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LoadError::Io { source, .. } => Some(source),
+            LoadError::Json { source, .. } => Some(source),
+            LoadError::Safetensors { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,266 @@
+// Cache-blocked GEMM for C = beta * C + alpha * A @ B^T with more than one row in A.
+// A (m, k) and B (n, k) are split into KC-deep slices; for every (MC x NC) block of C
+// the matching slices are packed into contiguous MR/NR-wide panels, which a register
+// micro-kernel then consumes as a sequence of rank-1 updates.
+use crate::simd::{self, Isa};
+use crate::weight::WeightMatrix;
+use rayon::prelude::*;
+
+// Micro-kernel tile: MR rows of A times NR rows of B
+pub const MR: usize = 6;
+pub const NR: usize = 16;
+// Cache blocking of the k, m and n dimensions
+const KC: usize = 256;
+const MC: usize = 8 * MR;
+const NC: usize = 16 * NR;
+
+// Raw pointer into C shared by the tasks, each of which writes a disjoint block
+#[derive(Clone, Copy)]
+struct CPtr(*mut f32);
+unsafe impl Send for CPtr {}
+unsafe impl Sync for CPtr {}
+
+impl CPtr {
+    unsafe fn add(self, offset: usize) -> Self {
+        CPtr(self.0.add(offset))
+    }
+}
+
+pub fn gemm_transb<B: WeightMatrix + ?Sized>(
+    pool: &rayon::ThreadPool,
+    m: usize,
+    beta: f32,
+    a: &[f32],
+    b: &B,
+    alpha: f32,
+    c: &mut [f32],
+) {
+    let (n, k) = (b.rows(), b.cols());
+    assert!(a.len() >= m * k && c.len() >= m * n);
+    if beta == 0. {
+        c[..m * n].fill(0.);
+    } else if beta != 1. {
+        c[..m * n].iter_mut().for_each(|x| *x *= beta);
+    }
+    if m == 0 || n == 0 || k == 0 {
+        return;
+    }
+
+    // Shrink the column blocks when there are too few row blocks to keep every worker busy
+    let m_blocks = m.div_ceil(MC);
+    let want_n_blocks = (pool.current_num_threads() * 2).div_ceil(m_blocks);
+    let nc = n.div_ceil(want_n_blocks).next_multiple_of(NR).clamp(NR, NC);
+    let tasks = (0..m_blocks)
+        .flat_map(|ib| (0..n.div_ceil(nc)).map(move |jb| (ib * MC, jb * nc)))
+        .collect::<Vec<_>>();
+
+    let c_ptr = CPtr(c.as_mut_ptr());
+    let isa = simd::isa();
+    let run = |(ic, jc): (usize, usize)| {
+        let mc = MC.min(m - ic);
+        let nc = nc.min(n - jc);
+        let mut a_pack = vec![0.; mc.next_multiple_of(MR) * KC];
+        let mut b_pack = vec![0.; nc.next_multiple_of(NR) * KC];
+        let mut row_buf = [0.; KC];
+        for pc in (0..k).step_by(KC) {
+            let kc = KC.min(k - pc);
+            pack_panels::<MR>(&mut a_pack, mc, kc, &mut row_buf, |r, dst| {
+                dst.copy_from_slice(&a[(ic + r) * k + pc..][..kc])
+            });
+            pack_panels::<NR>(&mut b_pack, nc, kc, &mut row_buf, |r, dst| {
+                b.row_to_f32(jc + r, pc, dst)
+            });
+            for jr in (0..nc).step_by(NR) {
+                for ir in (0..mc).step_by(MR) {
+                    let a_panel = &a_pack[ir * kc..][..MR * kc];
+                    let b_panel = &b_pack[jr * kc..][..NR * kc];
+                    let mut acc = [0.; MR * NR];
+                    micro_kernel(isa, kc, a_panel, b_panel, &mut acc);
+                    // This task is the only writer of rows ic.., columns jc.. of C
+                    let c_tile = unsafe { c_ptr.add((ic + ir) * n + jc + jr) };
+                    store_tile(c_tile, n, alpha, &acc, MR.min(mc - ir), NR.min(nc - jr));
+                }
+            }
+        }
+    };
+
+    if tasks.len() == 1 {
+        run(tasks[0]);
+    } else {
+        pool.install(|| tasks.into_par_iter().for_each(run));
+    }
+}
+
+// Pack `rows` rows of kc values into W-wide panels laid out as [panel][p][W], i.e.
+// element (r, p) goes to (r / W) * W * kc + p * W + r % W. Each source row is first
+// written to `row_buf` as f32 by `read_row(r, row_buf)`; rows past the end of the
+// last panel are zero-filled.
+fn pack_panels<const W: usize>(
+    dst: &mut [f32],
+    rows: usize,
+    kc: usize,
+    row_buf: &mut [f32; KC],
+    read_row: impl Fn(usize, &mut [f32]),
+) {
+    for (panel, r0) in (0..rows).step_by(W).enumerate() {
+        let out = &mut dst[panel * W * kc..][..W * kc];
+        for r in 0..W {
+            if r0 + r < rows {
+                let row = &mut row_buf[..kc];
+                read_row(r0 + r, row);
+                for (p, &x) in row.iter().enumerate() {
+                    out[p * W + r] = x;
+                }
+            } else {
+                (0..kc).for_each(|p| out[p * W + r] = 0.);
+            }
+        }
+    }
+}
+
+fn store_tile(c: CPtr, ldc: usize, alpha: f32, acc: &[f32; MR * NR], rows: usize, cols: usize) {
+    for i in 0..rows {
+        let c_row = unsafe { std::slice::from_raw_parts_mut(c.0.add(i * ldc), cols) };
+        simd::axpy(c_row, alpha, &acc[i * NR..][..cols]);
+    }
+}
+
+// acc[i * NR + j] = sum_p a[p * MR + i] * b[p * NR + j]
+#[inline]
+fn micro_kernel(isa: Isa, kc: usize, a: &[f32], b: &[f32], acc: &mut [f32; MR * NR]) {
+    assert!(a.len() >= kc * MR && b.len() >= kc * NR);
+    match isa {
+        #[cfg(target_arch = "x86_64")]
+        Isa::Avx2Fma => unsafe { avx2::micro_kernel(kc, a, b, acc) },
+        #[cfg(target_arch = "aarch64")]
+        Isa::Neon => unsafe { neon::micro_kernel(kc, a, b, acc) },
+        _ => scalar_micro_kernel(kc, a, b, acc),
+    }
+}
+
+pub fn scalar_micro_kernel(kc: usize, a: &[f32], b: &[f32], acc: &mut [f32; MR * NR]) {
+    for p in 0..kc {
+        let a_p = &a[p * MR..][..MR];
+        let b_p = &b[p * NR..][..NR];
+        for i in 0..MR {
+            for j in 0..NR {
+                acc[i * NR + j] += a_p[i] * b_p[j];
+            }
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+pub mod avx2 {
+    use super::{MR, NR};
+    use std::arch::x86_64::*;
+
+    // 6 x 16 tile held in 12 ymm accumulators
+    #[target_feature(enable = "avx2,fma")]
+    pub unsafe fn micro_kernel(kc: usize, a: &[f32], b: &[f32], acc: &mut [f32; MR * NR]) {
+        let (pa, pb) = (a.as_ptr(), b.as_ptr());
+        let mut c = [[_mm256_setzero_ps(); 2]; MR];
+        for p in 0..kc {
+            let b0 = _mm256_loadu_ps(pb.add(p * NR));
+            let b1 = _mm256_loadu_ps(pb.add(p * NR + 8));
+            for (i, c) in c.iter_mut().enumerate() {
+                let a = _mm256_broadcast_ss(&*pa.add(p * MR + i));
+                c[0] = _mm256_fmadd_ps(a, b0, c[0]);
+                c[1] = _mm256_fmadd_ps(a, b1, c[1]);
+            }
+        }
+        let out = acc.as_mut_ptr();
+        for (i, c) in c.iter().enumerate() {
+            _mm256_storeu_ps(out.add(i * NR), c[0]);
+            _mm256_storeu_ps(out.add(i * NR + 8), c[1]);
+        }
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+pub mod neon {
+    use super::{MR, NR};
+    use std::arch::aarch64::*;
+
+    // 6 x 16 tile held in 24 q-register accumulators
+    #[target_feature(enable = "neon")]
+    pub unsafe fn micro_kernel(kc: usize, a: &[f32], b: &[f32], acc: &mut [f32; MR * NR]) {
+        let (pa, pb) = (a.as_ptr(), b.as_ptr());
+        let mut c = [[vdupq_n_f32(0.); 4]; MR];
+        for p in 0..kc {
+            let bv = [
+                vld1q_f32(pb.add(p * NR)),
+                vld1q_f32(pb.add(p * NR + 4)),
+                vld1q_f32(pb.add(p * NR + 8)),
+                vld1q_f32(pb.add(p * NR + 12)),
+            ];
+            for (i, c) in c.iter_mut().enumerate() {
+                let a = *pa.add(p * MR + i);
+                for (c, &b) in c.iter_mut().zip(&bv) {
+                    *c = vfmaq_n_f32(*c, b, a);
+                }
+            }
+        }
+        let out = acc.as_mut_ptr();
+        for (i, c) in c.iter().enumerate() {
+            for (j, &c) in c.iter().enumerate() {
+                vst1q_f32(out.add(i * NR + j * 4), c);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_micro_kernel() {
+    let kc = 37;
+    let a = (0..kc * MR)
+        .map(|x| (x % 11) as f32 * 0.25 - 1.0)
+        .collect::<Vec<_>>();
+    let b = (0..kc * NR)
+        .map(|x| (x % 7) as f32 * 0.5 - 1.5)
+        .collect::<Vec<_>>();
+    let mut expected = [0.; MR * NR];
+    scalar_micro_kernel(kc, &a, &b, &mut expected);
+    #[cfg(target_arch = "x86_64")]
+    if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
+        let mut acc = [0.; MR * NR];
+        unsafe { avx2::micro_kernel(kc, &a, &b, &mut acc) };
+        assert_eq!(acc, expected);
+    }
+    #[cfg(target_arch = "aarch64")]
+    if std::arch::is_aarch64_feature_detected!("neon") {
+        let mut acc = [0.; MR * NR];
+        unsafe { neon::micro_kernel(kc, &a, &b, &mut acc) };
+        assert_eq!(acc, expected);
+    }
+}
+
+#[test]
+fn test_gemm_transb() {
+    use crate::tensor::Tensor;
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(3)
+        .build()
+        .unwrap();
+    for (m, n, k) in [(2, 3, 5), (7, 17, 300), (50, 33, 257), (13, 300, 64)] {
+        let a = (0..m * k)
+            .map(|x| (x % 17) as f32 * 0.125 - 1.0)
+            .collect::<Vec<_>>();
+        let b = (0..n * k)
+            .map(|x| (x % 13) as f32 * 0.25 - 1.5)
+            .collect::<Vec<_>>();
+        for beta in [0., 1., 0.5] {
+            let mut c = (0..m * n).map(|x| (x % 5) as f32).collect::<Vec<_>>();
+            let mut expected = c.clone();
+            for i in 0..m {
+                for j in 0..n {
+                    let sum = simd::scalar::dot(&a[i * k..][..k], &b[j * k..][..k]);
+                    expected[i * n + j] = beta * expected[i * n + j] + 2. * sum;
+                }
+            }
+            let b = Tensor::<f32>::new(b.clone(), &vec![n, k]);
+            gemm_transb(&pool, m, beta, &a, &b, 2., &mut c);
+            assert_eq!(c, expected, "m={m} n={n} k={k} beta={beta}");
+        }
+    }
+}
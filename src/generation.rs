@@ -0,0 +1,408 @@
+// Sampling settings and stop criteria for `Llama::generate` and `Llama::stream_generate`.
+// The defaults come from the model's generation_config.json; fields it leaves out take the
+// same defaults as Hugging Face transformers; in particular without "do_sample": true the
+// model decodes greedily. Callers override them builder-style:
+//     llama.generation_config().clone().do_sample(true).temperature(0.7).max_new_tokens(200)
+use crate::config::{deserialize_token_ids, read_json, LlamaConfigJson};
+use crate::error::LoadError;
+use crate::sampling::{self, LogitsProcessor, LogitsProcessors};
+use std::path::Path;
+use std::sync::Arc;
+
+#[derive(serde::Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct GenerationConfig {
+    // Sample the next token; false picks the most likely one and ignores temperature,
+    // top-k, top-p, min-p, typical-p and Mirostat. None (not set in the file) decodes
+    // greedily like false, but lets the caller choose its own default.
+    pub do_sample: Option<bool>,
+    pub temperature: f32,
+    pub top_p: f32,
+    // 0 keeps every token
+    pub top_k: u32,
+    // Keep tokens at least this times as likely as the most likely one; 0 disables it
+    pub min_p: f32,
+    // Locally typical sampling mass; 1 disables it
+    pub typical_p: f32,
+    // Sample with Mirostat v2 instead of top-k / top-p / min-p / typical-p
+    #[serde(skip)]
+    pub mirostat: Option<MirostatConfig>,
+    // > 1 makes tokens that were already generated less likely, 1 disables it
+    pub repetition_penalty: f32,
+    // Subtracted from a token's logit once per time it was generated, 0 disables it
+    #[serde(skip)]
+    pub frequency_penalty: f32,
+    // Subtracted from the logit of every token that was generated at all, 0 disables it
+    #[serde(skip)]
+    pub presence_penalty: f32,
+    // The penalties only count the last this many generated tokens; None counts all of them
+    #[serde(skip)]
+    pub penalty_window: Option<usize>,
+    // Seed of the sampling RNG, owned by each generate / stream_generate call. With a seed the
+    // same prompt and config always give the same tokens; None seeds from the OS.
+    #[serde(skip)]
+    pub seed: Option<u64>,
+    // Number of tokens generated after the prompt; None only stops at the EOS ids, a stop
+    // sequence or the end of the KV cache
+    pub max_new_tokens: Option<usize>,
+    // Generation stops at any of these
+    #[serde(deserialize_with = "deserialize_token_ids")]
+    pub eos_token_id: Vec<u32>,
+    // Generation also stops once the generated tokens end with one of these sequences
+    #[serde(skip)]
+    pub stop_sequences: Vec<Vec<u32>>,
+    // (token, bias) pairs added to the logits; a bias of -inf bans the token. Tokens
+    // outside the vocabulary are ignored
+    #[serde(skip)]
+    pub logit_bias: Vec<(u32, f32)>,
+    // Run after the built-in processors, see `SamplingPipeline::from_config`
+    #[serde(skip)]
+    pub logits_processors: LogitsProcessors,
+    // > 1 makes `Llama::generate` run beam search with this many beams instead of sampling
+    pub num_beams: usize,
+    // Beam scores are sum_logprobs / generated_len ^ length_penalty; > 0 favours longer
+    // sequences, < 0 shorter ones
+    pub length_penalty: f32,
+    // Stop beam search as soon as num_beams beams have finished
+    pub early_stopping: bool,
+    // Number of most likely alternatives `Llama::generate_with_logprobs` reports per token
+    #[serde(skip)]
+    pub top_logprobs: usize,
+}
+
+// Mirostat v2 holds the surprise -log2(p) of the sampled tokens around tau, adapting its
+// truncation threshold with learning rate eta
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MirostatConfig {
+    pub tau: f32,
+    pub eta: f32,
+}
+
+impl Default for GenerationConfig {
+    fn default() -> Self {
+        GenerationConfig {
+            do_sample: None,
+            temperature: 1.0,
+            top_p: 1.0,
+            top_k: 50,
+            min_p: 0.0,
+            typical_p: 1.0,
+            mirostat: None,
+            repetition_penalty: 1.0,
+            frequency_penalty: 0.0,
+            presence_penalty: 0.0,
+            penalty_window: None,
+            seed: None,
+            max_new_tokens: None,
+            eos_token_id: vec![],
+            stop_sequences: vec![],
+            logit_bias: vec![],
+            logits_processors: LogitsProcessors::default(),
+            num_beams: 1,
+            length_penalty: 1.0,
+            early_stopping: false,
+            top_logprobs: 0,
+        }
+    }
+}
+
+impl GenerationConfig {
+    // Read generation_config.json when the model ships one, taking the EOS ids from
+    // config.json when it has none
+    pub fn from_model_dir(model_dir: &Path, config: &LlamaConfigJson) -> Result<Self, LoadError> {
+        let path = model_dir.join("generation_config.json");
+        let mut generation_config = if path.exists() {
+            read_json(&path)?
+        } else {
+            GenerationConfig::default()
+        };
+        if generation_config.eos_token_id.is_empty() {
+            generation_config.eos_token_id = config.eos_token_id.clone();
+        }
+        Ok(generation_config)
+    }
+
+    // Whether generation is over after `generated`, the tokens produced after the prompt
+    pub fn should_stop(&self, generated: &[u32]) -> bool {
+        if self
+            .max_new_tokens
+            .is_some_and(|max| generated.len() >= max)
+        {
+            return true;
+        }
+        let Some(last) = generated.last() else {
+            return false;
+        };
+        self.eos_token_id.contains(last)
+            || self
+                .stop_sequences
+                .iter()
+                .any(|stop| !stop.is_empty() && generated.ends_with(stop))
+    }
+}
+
+// Builder-style overrides; the binary only sets max_new_tokens, embedders use the rest
+#[allow(unused)]
+impl GenerationConfig {
+    pub fn do_sample(mut self, do_sample: bool) -> Self {
+        self.do_sample = Some(do_sample);
+        self
+    }
+
+    pub fn temperature(mut self, temperature: f32) -> Self {
+        self.temperature = temperature;
+        self
+    }
+
+    pub fn top_p(mut self, top_p: f32) -> Self {
+        self.top_p = top_p;
+        self
+    }
+
+    pub fn top_k(mut self, top_k: u32) -> Self {
+        self.top_k = top_k;
+        self
+    }
+
+    pub fn min_p(mut self, min_p: f32) -> Self {
+        self.min_p = min_p;
+        self
+    }
+
+    pub fn typical_p(mut self, typical_p: f32) -> Self {
+        self.typical_p = typical_p;
+        self
+    }
+
+    // Mirostat is a way of sampling, so it also turns sampling on
+    pub fn mirostat(mut self, tau: f32, eta: f32) -> Self {
+        self.mirostat = Some(MirostatConfig { tau, eta });
+        self.do_sample = Some(true);
+        self
+    }
+
+    pub fn repetition_penalty(mut self, repetition_penalty: f32) -> Self {
+        self.repetition_penalty = repetition_penalty;
+        self
+    }
+
+    pub fn frequency_penalty(mut self, frequency_penalty: f32) -> Self {
+        self.frequency_penalty = frequency_penalty;
+        self
+    }
+
+    pub fn presence_penalty(mut self, presence_penalty: f32) -> Self {
+        self.presence_penalty = presence_penalty;
+        self
+    }
+
+    pub fn penalty_window(mut self, penalty_window: usize) -> Self {
+        self.penalty_window = Some(penalty_window);
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    pub fn max_new_tokens(mut self, max_new_tokens: usize) -> Self {
+        self.max_new_tokens = Some(max_new_tokens);
+        self
+    }
+
+    pub fn eos_token_ids(mut self, eos_token_ids: Vec<u32>) -> Self {
+        self.eos_token_id = eos_token_ids;
+        self
+    }
+
+    pub fn stop_sequence(mut self, stop_sequence: Vec<u32>) -> Self {
+        self.stop_sequences.push(stop_sequence);
+        self
+    }
+
+    pub fn logit_bias(mut self, token: u32, bias: f32) -> Self {
+        self.logit_bias.push((token, bias));
+        self
+    }
+
+    pub fn logits_processor(mut self, processor: Arc<dyn LogitsProcessor>) -> Self {
+        self.logits_processors.0.push(processor);
+        self
+    }
+
+    pub fn num_beams(mut self, num_beams: usize) -> Self {
+        self.num_beams = num_beams;
+        self
+    }
+
+    pub fn length_penalty(mut self, length_penalty: f32) -> Self {
+        self.length_penalty = length_penalty;
+        self
+    }
+
+    pub fn early_stopping(mut self, early_stopping: bool) -> Self {
+        self.early_stopping = early_stopping;
+        self
+    }
+
+    pub fn top_logprobs(mut self, top_logprobs: usize) -> Self {
+        self.top_logprobs = top_logprobs;
+        self
+    }
+}
+
+// A generated token with its log-probability and the most likely tokens at its position,
+// like an entry of the OpenAI `logprobs` field. The log-probabilities are those of the
+// model itself, before penalties, temperature and truncation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TokenLogprobs {
+    pub token: u32,
+    pub logprob: f32,
+    pub top_logprobs: Vec<(u32, f32)>, // (token, logprob), most likely first
+}
+
+impl TokenLogprobs {
+    // `logprobs` is the log-softmax of the logits `token` was sampled from
+    pub fn new(logprobs: &[f32], token: u32, n: usize) -> Self {
+        TokenLogprobs {
+            token,
+            logprob: logprobs[token as usize],
+            top_logprobs: sampling::top_n(logprobs, n),
+        }
+    }
+}
+
+// The best num_beams finished beams of a beam search, ranked by their length-penalized score
+pub struct BeamHypotheses {
+    num_beams: usize,
+    length_penalty: f32,
+    early_stopping: bool,
+    beams: Vec<(f32, Vec<u32>)>, // (score, generated tokens), best first
+}
+
+impl BeamHypotheses {
+    pub fn new(config: &GenerationConfig) -> Self {
+        BeamHypotheses {
+            num_beams: config.num_beams.max(1),
+            length_penalty: config.length_penalty,
+            early_stopping: config.early_stopping,
+            beams: vec![],
+        }
+    }
+
+    fn score(&self, sum_logprobs: f32, generated_len: usize) -> f32 {
+        sum_logprobs / (generated_len as f32).powf(self.length_penalty)
+    }
+
+    pub fn add(&mut self, tokens: Vec<u32>, sum_logprobs: f32) {
+        let score = self.score(sum_logprobs, tokens.len());
+        let i = self.beams.partition_point(|(s, _)| *s >= score);
+        self.beams.insert(i, (score, tokens));
+        self.beams.truncate(self.num_beams);
+    }
+
+    // Whether the search can stop: num_beams beams have finished and, without early
+    // stopping, the best live beam would not beat the worst of them if it ended now
+    pub fn is_done(&self, best_sum_logprobs: f32, generated_len: usize) -> bool {
+        let Some((worst, _)) = self.beams.get(self.num_beams - 1) else {
+            return false;
+        };
+        self.early_stopping || self.score(best_sum_logprobs, generated_len) <= *worst
+    }
+
+    pub fn best(mut self) -> Option<Vec<u32>> {
+        (!self.beams.is_empty()).then(|| self.beams.swap_remove(0).1)
+    }
+}
+
+#[test]
+fn test_generation_config() {
+    use crate::sampling::SamplingPipeline;
+    use crate::tensor::Tensor;
+    use std::path::PathBuf;
+    let project_dir = env!("CARGO_MANIFEST_DIR");
+    let model_dir = PathBuf::from(project_dir).join("models").join("story");
+    let config = LlamaConfigJson::from_model_dir(&model_dir).unwrap();
+
+    // 在接近均匀的 logits 上采样 50 次，返回出现过的 tokens
+    let picks = |config: &GenerationConfig| {
+        let logits = || Tensor::<f32>::new(vec![1., 1.1, 1., 1., 1.05], &vec![5]);
+        let mut sampling = SamplingPipeline::from_config(&config.clone().seed(0));
+        (0..50)
+            .map(|_| sampling.sample(logits(), &[]))
+            .collect::<std::collections::BTreeSet<_>>()
+    };
+
+    // story 的 generation_config.json 只有 token id，其余取默认值；没有 do_sample 时贪心解码
+    let generation_config = GenerationConfig::from_model_dir(&model_dir, &config).unwrap();
+    assert_eq!(
+        generation_config,
+        GenerationConfig {
+            eos_token_id: vec![2],
+            ..GenerationConfig::default()
+        }
+    );
+    assert_eq!(generation_config.do_sample, None);
+    assert_eq!(picks(&generation_config), [1].into());
+
+    let test_dir = std::env::temp_dir().join(format!("generation-{}", std::process::id()));
+    std::fs::create_dir_all(&test_dir).unwrap();
+    std::fs::write(
+        test_dir.join("generation_config.json"),
+        r#"{"temperature": 0.7, "top_p": 0.9, "top_k": 20, "min_p": 0.05, "typical_p": 0.95,
+            "repetition_penalty": 1.1, "num_beams": 4, "length_penalty": 0.8,
+            "early_stopping": true, "max_new_tokens": 64, "eos_token_id": [2, 5], "do_sample": true}"#,
+    )
+    .unwrap();
+    let generation_config = GenerationConfig::from_model_dir(&test_dir, &config).unwrap();
+    std::fs::remove_dir_all(&test_dir).unwrap();
+    assert_eq!(
+        generation_config,
+        GenerationConfig {
+            do_sample: Some(true),
+            temperature: 0.7,
+            top_p: 0.9,
+            top_k: 20,
+            min_p: 0.05,
+            typical_p: 0.95,
+            repetition_penalty: 1.1,
+            max_new_tokens: Some(64),
+            eos_token_id: vec![2, 5],
+            num_beams: 4,
+            length_penalty: 0.8,
+            early_stopping: true,
+            ..GenerationConfig::default()
+        }
+    );
+    assert!(picks(&generation_config).len() > 1);
+}
+
+#[test]
+fn test_beam_hypotheses() {
+    let config = GenerationConfig::default().num_beams(2);
+    let mut hypotheses = BeamHypotheses::new(&config);
+    hypotheses.add(vec![1, 2, 3, 4], -4.);
+    assert!(!hypotheses.is_done(-1., 2));
+    hypotheses.add(vec![5, 6], -3.);
+    hypotheses.add(vec![7], -2.5);
+    // 分数为 -1、-1.5、-2.5，只保留最好的两个
+    assert_eq!(hypotheses.beams.len(), 2);
+    assert!(!hypotheses.is_done(-2., 2));
+    assert!(hypotheses.is_done(-4., 2));
+    assert!(BeamHypotheses {
+        early_stopping: true,
+        ..hypotheses
+    }
+    .is_done(0., 1));
+
+    // length_penalty = 0 时只比较 logprob 之和，偏向短序列
+    let mut hypotheses = BeamHypotheses::new(&config.clone().length_penalty(0.));
+    hypotheses.add(vec![1, 2, 3, 4], -4.);
+    hypotheses.add(vec![7], -2.5);
+    assert_eq!(hypotheses.best(), Some(vec![7]));
+    let mut hypotheses = BeamHypotheses::new(&config);
+    hypotheses.add(vec![1, 2, 3, 4], -4.);
+    hypotheses.add(vec![7], -2.5);
+    assert_eq!(hypotheses.best(), Some(vec![1, 2, 3, 4]));
+}
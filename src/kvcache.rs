@@ -1,10 +1,9 @@
-use std::{usize, vec};
+use std::vec;
 
 use crate::tensor::Tensor;
 pub struct KVCache<T> {
     k_cache: Vec<Tensor<T>>, // (max_seq_len, n_kv_head * dqkv) x layers
     v_cache: Vec<Tensor<T>>, // (max_seq_len, n_kv_head * dqkv) x layers
-    #[allow(unused)]
     max_seq_len: usize,
     dim: usize,
     length: usize, // length of the current sequence
@@ -19,8 +18,8 @@ impl<T: Default + Copy> KVCache<T> {
             v_cache: (0..n_layers)
                 .map(|_| Tensor::default(&vec![max_seq_len, dim]))
                 .collect(),
-            max_seq_len: max_seq_len,
-            dim: dim,
+            max_seq_len,
+            dim,
             length: init_len,
         }
     }
@@ -33,11 +32,38 @@ impl<T: Default + Copy> KVCache<T> {
         self.v_cache[layer].slice(start * self.dim, &vec![self.length - start, self.dim])
     }
 
-    pub fn increment(&mut self, seq_len: usize){
+    pub fn increment(&mut self, seq_len: usize) {
         self.length += seq_len;
     }
 
     pub fn len(&self) -> usize {
         self.length
     }
+
+    // Number of positions the cache can hold
+    pub fn max_len(&self) -> usize {
+        self.max_seq_len
+    }
+
+    // An independent copy of the filled positions, e.g. for beams that share a prompt prefill
+    pub fn fork(&self) -> Self {
+        let copy = |caches: &Vec<Tensor<T>>| {
+            caches
+                .iter()
+                .map(|cache| {
+                    let mut copy = Tensor::default(&vec![self.max_seq_len, self.dim]);
+                    let filled = self.length * self.dim;
+                    unsafe { copy.data_mut()[..filled].copy_from_slice(&cache.data()[..filled]) };
+                    copy
+                })
+                .collect()
+        };
+        KVCache {
+            k_cache: copy(&self.k_cache),
+            v_cache: copy(&self.v_cache),
+            max_seq_len: self.max_seq_len,
+            dim: self.dim,
+            length: self.length,
+        }
+    }
 }
@@ -1,12 +1,25 @@
+mod checkpoint;
 mod config;
+mod dtype;
+mod error;
+mod gemm;
+mod generation;
 mod kvcache;
 mod model;
 mod operators;
 mod params;
+mod perplexity;
+mod quant;
+mod sampling;
+mod simd;
 mod tensor;
+mod weight;
 
+use dtype::WeightType;
+use error::LoadError;
+use half::{bf16, f16};
 use std::io::{self, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use tokenizers::Tokenizer;
 
 struct Message {
@@ -20,30 +33,162 @@ impl Message {
     }
 }
 
-fn main() {
-    let mode = "chat"; // "story"、"chat"
+const USAGE: &str = "usage: learning-lm-rust [chat | story | perplexity <text file> [stride]]";
 
-    let project_dir = env!("CARGO_MANIFEST_DIR");
-    let model_dir = PathBuf::from(project_dir).join("models").join(mode);
-    let llama = model::Llama::<f32>::from_safetensors(&model_dir);
+fn main() -> Result<(), LoadError> {
+    // 第一个参数选择模式，默认为 chat
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let mode = args.first().map_or("chat", String::as_str);
+    let mode_args = args.get(1..).unwrap_or(&[]);
+    if !["chat", "story", "perplexity"].contains(&mode) {
+        eprintln!("{USAGE}");
+        std::process::exit(2);
+    }
+
+    // LM_NUM_THREADS 控制算子线程数，默认使用全部核心
+    if let Some(n_threads) = std::env::var("LM_NUM_THREADS")
+        .ok()
+        .and_then(|n| n.parse().ok())
+    {
+        operators::set_num_threads(n_threads);
+    }
+
+    // LM_MODEL_DIR 指定模型目录，例如转换或量化后的 checkpoint；
+    // 默认 chat 使用 models/chat，其余模式使用 models/story
+    let model_dir = match std::env::var_os("LM_MODEL_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => {
+            let project_dir = env!("CARGO_MANIFEST_DIR");
+            let model = if mode == "chat" { "chat" } else { "story" };
+            PathBuf::from(project_dir).join("models").join(model)
+        }
+    };
+
+    // 权重按 torch_dtype 的精度保存在内存中，半精度模型只占用一半内存；
+    // 其他或缺省的 torch_dtype 按 f32 保存，各 tensor 的实际 dtype 在加载时检查
+    let config = config::LlamaConfigJson::from_model_dir(&model_dir)?;
+    match config.torch_dtype.as_str() {
+        "float16" => run::<f16>(mode, mode_args, &model_dir),
+        "bfloat16" => run::<bf16>(mode, mode_args, &model_dir),
+        _ => run::<f32>(mode, mode_args, &model_dir),
+    }
+}
+
+fn run<T: WeightType>(mode: &str, args: &[String], model_dir: &Path) -> Result<(), LoadError> {
+    // LM_QUANT 在加载时量化矩阵，例如 "q8_0" 或 "attention=q4_0,ffn=q4_0,lm_head=q8_0"
+    // 未设置时加载原精度模型，无法解析时报错退出
+    let llama = match std::env::var("LM_QUANT").ok() {
+        Some(spec) => match quant::QuantConfig::parse(&spec) {
+            Some(quant) => model::Llama::<T>::from_safetensors_quantized(model_dir, &quant)?,
+            None => {
+                eprintln!("invalid LM_QUANT {spec:?}, expected e.g. \"q8_0\" or \"ffn=q4_0\"");
+                std::process::exit(2);
+            }
+        },
+        None => model::Llama::<T>::from_safetensors(model_dir)?,
+    };
     let tokenizer = Tokenizer::from_file(model_dir.join("tokenizer.json")).unwrap();
     if mode == "chat" {
-        chat(&llama, &tokenizer, 1.0);
+        chat(&llama, &tokenizer);
     } else if mode == "story" {
         let input = "Once upon a time";
         let binding = tokenizer.encode(input, true).unwrap();
         let input_ids = binding.get_ids();
-        let output_ids = llama.generate(input_ids, 500, 0.8, 30, 1.0);
+        let generation_config = generation_config(&llama);
+        let output_ids = llama.generate(input_ids, &generation_config);
         println!("{}", tokenizer.decode(&output_ids, true).unwrap());
+    } else if mode == "perplexity" {
+        perplexity(&llama, &tokenizer, args)?;
     }
+    Ok(())
 }
-fn chat(llama: &model::Llama<f32>, tokenizer: &Tokenizer, temperature: f32) {
+
+// 用模型的 next-token logits 计算文本文件的困惑度：窗口长度为 max_position_embeddings，
+// 每次滑动 stride 个 token（默认半个窗口）
+fn perplexity<T: WeightType>(
+    llama: &model::Llama<T>,
+    tokenizer: &Tokenizer,
+    args: &[String],
+) -> Result<(), LoadError> {
+    let window = llama.max_seq_len();
+    let (path, stride) = match args {
+        [path] => (Path::new(path), window / 2),
+        [path, stride] => match stride.parse() {
+            Ok(stride) if stride > 0 && stride <= window => (Path::new(path), stride),
+            _ => {
+                eprintln!("stride must be between 1 and {window}");
+                std::process::exit(2);
+            }
+        },
+        _ => {
+            eprintln!("{USAGE}");
+            std::process::exit(2);
+        }
+    };
+    let text = std::fs::read_to_string(path).map_err(|source| LoadError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let encoding = tokenizer.encode(text, true).unwrap();
+    let result = perplexity::evaluate(llama, encoding.get_ids(), window, stride);
+    if result.tokens == 0 {
+        eprintln!("{} has no tokens to score", path.display());
+        std::process::exit(2);
+    }
+    println!(
+        "{} tokens scored (window {window}, stride {stride}): perplexity {:.4}, {:.4} bits per token",
+        result.tokens,
+        result.ppl(),
+        result.bits_per_token()
+    );
+    Ok(())
+}
+
+// generation_config.json 的设置。文件没有设置 do_sample 时沿用之前的采样参数
+// top_p = 0.8、top_k = 30；LM_SEED 固定采样的随机种子以便复现，并且意味着采样；
+// LM_DO_SAMPLE=0 或 1 强制贪心解码或采样
+fn generation_config<T: WeightType>(llama: &model::Llama<T>) -> generation::GenerationConfig {
+    let mut config = llama.generation_config().clone().max_new_tokens(500);
+    if config.do_sample.is_none() {
+        config = config.do_sample(true).top_p(0.8).top_k(30);
+    }
+    if let Ok(seed) = std::env::var("LM_SEED") {
+        let Ok(seed) = seed.parse() else {
+            eprintln!("invalid LM_SEED {seed:?}, expected an unsigned integer");
+            std::process::exit(2);
+        };
+        config = config.seed(seed).do_sample(true);
+    }
+    match std::env::var("LM_DO_SAMPLE").as_deref() {
+        Ok("0") => {
+            if config.seed.is_some() {
+                eprintln!("warning: LM_SEED has no effect with LM_DO_SAMPLE=0 (greedy decoding)");
+            }
+            config = config.do_sample(false);
+        }
+        Ok("1") => config = config.do_sample(true),
+        Ok(value) => {
+            eprintln!("invalid LM_DO_SAMPLE {value:?}, expected 0 or 1");
+            std::process::exit(2);
+        }
+        Err(_) => {}
+    }
+    config
+}
+
+fn chat<T: WeightType>(llama: &model::Llama<T>, tokenizer: &Tokenizer) {
     let mut kvcache = llama.new_cache();
+    // 对话逐个输出 token，不支持 beam search
+    let mut generation_config = generation_config(llama);
+    if generation_config.num_beams > 1 {
+        eprintln!("warning: chat streams its output and ignores num_beams > 1");
+        generation_config = generation_config.num_beams(1);
+    }
     let mut conversation_history: Vec<Message> = vec![]; //存储Message结构对话消息
     let mut formatted_input = String::new(); // 存储经过Jinja2模板格式化后的对话输入
 
     loop {
-        print!("User: \n");
+        println!("User: ");
         io::stdout().flush().unwrap();
 
         // 读取用户输入
@@ -75,8 +220,7 @@ fn chat(llama: &model::Llama<f32>, tokenizer: &Tokenizer, temperature: f32) {
         io::stdout().flush().unwrap();
 
         // 调用模型的 stream_generate 方法生成模型的回答
-        let response_tokens =
-            llama.stream_generate(input_ids, 500, 0.8, 30, temperature, &mut kvcache);
+        let response_tokens = llama.stream_generate(input_ids, &generation_config, &mut kvcache);
         for token in response_tokens {
             generated_tokens.push(token);
         }
@@ -1,12 +1,18 @@
-use std::fs::File;
 use std::vec;
 
+use crate::checkpoint::Checkpoint;
 use crate::config::LlamaConfigJson;
+use crate::dtype::WeightType;
+use crate::error::LoadError;
+use crate::generation::{BeamHypotheses, GenerationConfig, TokenLogprobs};
 use crate::kvcache::KVCache;
 use crate::operators as OP;
 use crate::params::LLamaParams;
+use crate::quant::QuantConfig;
+use crate::sampling::{self, SamplingPipeline};
+use crate::simd;
 use crate::tensor::Tensor;
-use safetensors::SafeTensors;
+use crate::weight::WeightMatrix;
 use std::path::Path;
 pub struct Llama<T> {
     vocab: usize,           // vocab size
@@ -20,19 +26,31 @@ pub struct Llama<T> {
     rope_theta: f32,        // rope theta for rope initialization
     max_seq_len: usize,     // maximum sequence length
     params: LLamaParams<T>, // trained weights of this model
-    bos_token_id: u32,      // start token id
-    eos_token_id: u32,      // end token id
+    #[allow(unused)]
+    bos_token_id: Option<u32>, // start token id
+    generation_config: GenerationConfig, // default sampling settings
 }
 
-impl Llama<f32> {
-    pub fn from_safetensors(model_dir: impl AsRef<Path>) -> Self {
-        let config = File::open(model_dir.as_ref().join("config.json")).unwrap();
-        let config: LlamaConfigJson = serde_json::from_reader(config).unwrap();
-        let model_file = std::fs::read(model_dir.as_ref().join("model.safetensors")).unwrap();
-        let safetensor = SafeTensors::deserialize(&model_file).unwrap();
-        let params = LLamaParams::from_safetensors(&safetensor, &config);
+impl<T: WeightType> Llama<T> {
+    pub fn from_safetensors(model_dir: impl AsRef<Path>) -> Result<Self, LoadError> {
+        Self::load(model_dir.as_ref(), &QuantConfig::default())
+    }
+
+    // Quantize each class of weight matrices to the format chosen in `quant` while loading
+    pub fn from_safetensors_quantized(
+        model_dir: impl AsRef<Path>,
+        quant: &QuantConfig,
+    ) -> Result<Self, LoadError> {
+        Self::load(model_dir.as_ref(), quant)
+    }
 
-        Self {
+    fn load(model_dir: &Path, quant: &QuantConfig) -> Result<Self, LoadError> {
+        let config = LlamaConfigJson::from_model_dir(model_dir)?;
+        let checkpoint = Checkpoint::from_model_dir(model_dir)?;
+        let params = LLamaParams::from_safetensors(&checkpoint, &config, quant)?;
+        let generation_config = GenerationConfig::from_model_dir(model_dir, &config)?;
+
+        Ok(Self {
             vocab: config.vocab_size,
             n_layers: config.num_hidden_layers,
             n_q_h: config.num_attention_heads,
@@ -43,17 +61,43 @@ impl Llama<f32> {
             eps: config.rms_norm_eps,
             rope_theta: config.rope_theta,
             max_seq_len: config.max_position_embeddings,
-            params: params,
+            params,
             bos_token_id: config.bos_token_id,
-            eos_token_id: config.eos_token_id,
-        }
+            generation_config,
+        })
+    }
+
+    // The settings of generation_config.json; override fields on a clone to change them
+    pub fn generation_config(&self) -> &GenerationConfig {
+        &self.generation_config
+    }
+
+    // max_position_embeddings, the longest sequence one KV cache holds
+    pub fn max_seq_len(&self) -> usize {
+        self.max_seq_len
     }
 
     pub fn new_cache(&self) -> KVCache<f32> {
         KVCache::new(self.n_layers, self.max_seq_len, self.n_kv_h * self.dqkv, 0)
     }
 
+    // Logits of the next token after `input`, (1, vocab)
     pub fn forward(&self, input: &Tensor<u32>, cache: &mut KVCache<f32>) -> Tensor<f32> {
+        let seq_len = input.size();
+        let residual = self.decode(input, cache);
+        // Only the last position predicts the next token
+        self.logits(&residual.slice((seq_len - 1) * self.d, &vec![1, self.d]))
+    }
+
+    // Logits of every position of `input`, (seq_len, vocab): row i scores the token that
+    // follows input[i]. One prefill pass is enough to score a whole text.
+    pub fn forward_all(&self, input: &Tensor<u32>, cache: &mut KVCache<f32>) -> Tensor<f32> {
+        let residual = self.decode(input, cache);
+        self.logits(&residual)
+    }
+
+    // Runs the decoder layers over `input` and returns the residual stream, (seq_len, d)
+    fn decode(&self, input: &Tensor<u32>, cache: &mut KVCache<f32>) -> Tensor<f32> {
         let seq_len = input.size();
         let past_seq_len = cache.len();
         cache.increment(seq_len);
@@ -81,9 +125,11 @@ impl Llama<f32> {
                 self.eps,
             );
 
-            let q = (&mut q_buf).reshape(&vec![seq_len, self.n_q_h * self.dqkv]); // (seq, n_h * dqkv)
+            let q = q_buf.reshape(&vec![seq_len, self.n_q_h * self.dqkv]); // (seq, n_h * dqkv)
             let k = &mut cache.k_cache(layer, past_seq_len); // (seq, n_kv_h * dqkv)
             let v = &mut cache.v_cache(layer, past_seq_len); // (seq, n_kv_h * dqkv)
+
+            // seq_len == 1 (decoding) makes these GEMVs, see OP::gemv_transb
             OP::matmul_transb(q, 0., &hidden_states, &self.params.wq[layer], 1.0);
             OP::matmul_transb(k, 0., &hidden_states, &self.params.wk[layer], 1.0);
             OP::matmul_transb(v, 0., &hidden_states, &self.params.wv[layer], 1.0);
@@ -133,15 +179,18 @@ impl Llama<f32> {
             );
         }
 
-        // No matter what seq_len, the output is always a 1D vector of length vocab,
-        // which contains the probabilities for the next token.
-        let mut logits = Tensor::<f32>::default(&vec![1, self.vocab]);
-        let mut hidden_states = hidden_states.slice((seq_len - 1) * self.d, &vec![1, self.d]);
-        let residual = residual.slice((seq_len - 1) * self.d, &vec![self.d]);
+        residual
+    }
+
+    // Final norm and lm_head: (n, d) hidden states to (n, vocab) logits
+    fn logits(&self, residual: &Tensor<f32>) -> Tensor<f32> {
+        let n = residual.size() / self.d;
+        let mut hidden_states = Tensor::<f32>::default(&vec![n, self.d]);
+        let mut logits = Tensor::<f32>::default(&vec![n, self.vocab]);
 
         OP::rms_norm(
             &mut hidden_states,
-            &residual,
+            residual,
             &self.params.rms_out_w,
             self.eps,
         );
@@ -151,61 +200,197 @@ impl Llama<f32> {
         logits
     }
 
-    pub fn generate(
+    // 返回 prompt 加上生成的 tokens，包括结束生成的 EOS；num_beams > 1 时使用 beam search
+    pub fn generate(&self, token_ids: &[u32], generation_config: &GenerationConfig) -> Vec<u32> {
+        if generation_config.num_beams > 1 {
+            return self.beam_search(token_ids, generation_config);
+        }
+        self.sample_tokens(token_ids, generation_config, false).0
+    }
+
+    // 与 generate 相同，另外返回每个生成的 token 的 logprob 及 top_logprobs 个最可能的候选。
+    // 只支持逐个采样，num_beams > 1 时 panic
+    #[allow(unused)]
+    pub fn generate_with_logprobs(
         &self,
         token_ids: &[u32],
-        max_len: usize,
-        top_p: f32,
-        top_k: u32,
-        temperature: f32,
-    ) -> Vec<u32> {
+        generation_config: &GenerationConfig,
+    ) -> (Vec<u32>, Vec<TokenLogprobs>) {
+        assert!(
+            generation_config.num_beams <= 1,
+            "generate_with_logprobs does not support beam search, use generate"
+        );
+        self.sample_tokens(token_ids, generation_config, true)
+    }
+
+    fn sample_tokens(
+        &self,
+        token_ids: &[u32],
+        generation_config: &GenerationConfig,
+        with_logprobs: bool,
+    ) -> (Vec<u32>, Vec<TokenLogprobs>) {
         let mut result_tokens = token_ids.to_vec();
+        let mut result_logprobs = vec![];
         let mut kvcache = self.new_cache();
+        let prompt_len = token_ids.len();
+        let mut sampling = SamplingPipeline::from_config(generation_config);
         let mut input_tensors: Tensor<u32> =
             Tensor::<u32>::new(result_tokens.clone(), &vec![result_tokens.len()]);
 
-        // 生成 tokens 直到达到 max_len 或遇到 EOS (end of sentence)
-        while result_tokens.len() < max_len {
+        // 生成 tokens 直到满足停止条件或 KV cache 已满
+        while !generation_config.should_stop(&result_tokens[prompt_len..])
+            && kvcache.len() + input_tensors.size() <= kvcache.max_len()
+        {
             // 调用 forward 函数计算 logits
             let logits = self.forward(&input_tensors, &mut kvcache);
+            // logprob 取自模型原始的 logits，不受惩罚项、temperature 和截断的影响
+            let logprobs = with_logprobs.then(|| sampling::log_softmax(logits.data()));
 
-            // 调用random sample函数根据logits的值生成下一个 token
-            let next_token = OP::random_sample(&logits, top_p, top_k, temperature);
+            // 经过 logits processor 链后采样下一个 token
+            let next_token = sampling.sample(logits, &result_tokens[prompt_len..]);
             result_tokens.push(next_token);
-
-            // 如果生成的 token 是 EOS (end of sentence)，则结束生成过程
-            if next_token == self.eos_token_id {
-                break;
+            if let Some(logprobs) = logprobs {
+                result_logprobs.push(TokenLogprobs::new(
+                    &logprobs,
+                    next_token,
+                    generation_config.top_logprobs,
+                ));
             }
+
             // 更新输入张量，将新生成的 token 作为下一个输入
             input_tensors = Tensor::<u32>::new(vec![next_token], &vec![1]);
         }
+        (result_tokens, result_logprobs)
+    }
+
+    // Beam search: each step extends every live beam by its 2 * num_beams most likely tokens
+    // and keeps the num_beams best candidates by summed log-probability, as in Hugging Face.
+    // Candidates that meet the stop criteria finish if they rank in the top num_beams. The
+    // prompt is prefilled once; every beam continues from a fork of its parent's KV cache.
+    // Returns the prompt plus the best finished beam.
+    pub fn beam_search(&self, token_ids: &[u32], generation_config: &GenerationConfig) -> Vec<u32> {
+        let num_beams = generation_config.num_beams.max(1);
+        let scoring = SamplingPipeline::scoring(generation_config);
+        let mut hypotheses = BeamHypotheses::new(generation_config);
+        let mut cache = self.new_cache();
+        if token_ids.len() > cache.max_len() {
+            return token_ids.to_vec();
+        }
+        let logits = self.forward(
+            &Tensor::<u32>::new(token_ids.to_vec(), &vec![token_ids.len()]),
+            &mut cache,
+        );
+        let mut beams = vec![Beam {
+            logprobs: sampling::log_softmax(scoring.process(logits, &[]).data()),
+            tokens: vec![],
+            score: 0.,
+            cache,
+        }];
+
+        loop {
+            let mut candidates = vec![]; // (score, parent beam, token)
+            for (i, beam) in beams.iter().enumerate() {
+                let top = sampling::top_n(&beam.logprobs, 2 * num_beams);
+                candidates.extend(
+                    top.into_iter()
+                        .filter(|(_, logprob)| *logprob > f32::NEG_INFINITY)
+                        .map(|(token, logprob)| (beam.score + logprob, i, token)),
+                );
+            }
+            candidates.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+            let mut next = vec![]; // (score, parent beam, tokens)
+            for (rank, (score, parent, token)) in candidates.into_iter().enumerate() {
+                let mut tokens = beams[parent].tokens.clone();
+                tokens.push(token);
+                if generation_config.should_stop(&tokens) {
+                    if rank < num_beams {
+                        hypotheses.add(tokens, score);
+                    }
+                } else {
+                    next.push((score, parent, tokens));
+                    if next.len() == num_beams {
+                        break;
+                    }
+                }
+            }
+            let Some((best_score, _, best_tokens)) = next.first() else {
+                break;
+            };
+            if hypotheses.is_done(*best_score, best_tokens.len()) {
+                break;
+            }
+            // KV cache 已满时，剩下的 beam 直接结束
+            if beams[0].cache.len() + 1 > beams[0].cache.max_len() {
+                for (score, _, tokens) in next {
+                    hypotheses.add(tokens, score);
+                }
+                break;
+            }
+
+            // 最后一个延续某个 beam 的候选接管它的 KV cache，其余的候选复制一份
+            let mut children = vec![0; beams.len()];
+            for (_, parent, _) in &next {
+                children[*parent] += 1;
+            }
+            let mut caches = beams.into_iter().map(|b| Some(b.cache)).collect::<Vec<_>>();
+            beams = next
+                .into_iter()
+                .map(|(score, parent, tokens)| {
+                    children[parent] -= 1;
+                    let mut cache = if children[parent] == 0 {
+                        caches[parent].take().unwrap()
+                    } else {
+                        caches[parent].as_ref().unwrap().fork()
+                    };
+                    let input = Tensor::<u32>::new(vec![*tokens.last().unwrap()], &vec![1]);
+                    let logits = self.forward(&input, &mut cache);
+                    Beam {
+                        logprobs: sampling::log_softmax(scoring.process(logits, &tokens).data()),
+                        tokens,
+                        score,
+                        cache,
+                    }
+                })
+                .collect();
+        }
+
+        let mut result_tokens = token_ids.to_vec();
+        result_tokens.extend(hypotheses.best().unwrap_or_default());
         result_tokens
     }
+
+    // 逐个返回生成的 tokens；EOS 不会被返回，停止序列的 tokens 会被返回。
+    // beam search 要到结束才能确定输出，无法流式返回，num_beams > 1 时 panic
     pub fn stream_generate<'a>(
         &'a self,
         token_ids: &[u32],
-        max_len: usize,
-        top_p: f32,
-        top_k: u32,
-        temperature: f32,
+        generation_config: &'a GenerationConfig,
         kvcache: &'a mut KVCache<f32>,
     ) -> impl Iterator<Item = u32> + 'a {
+        assert!(
+            generation_config.num_beams <= 1,
+            "stream_generate does not support beam search, use generate"
+        );
         let mut result_tokens = token_ids.to_vec();
+        let prompt_len = token_ids.len();
+        let mut sampling = SamplingPipeline::from_config(generation_config);
         let mut input_tensors =
             Tensor::<u32>::new(result_tokens.clone(), &vec![result_tokens.len()]);
 
         std::iter::from_fn(move || {
-            if result_tokens.len() >= max_len {
+            if generation_config.should_stop(&result_tokens[prompt_len..])
+                || kvcache.len() + input_tensors.size() > kvcache.max_len()
+            {
                 return None;
             }
 
             let logits = self.forward(&input_tensors, kvcache);
-            let next_token = OP::random_sample(&logits, top_p, top_k, temperature);
+            let next_token = sampling.sample(logits, &result_tokens[prompt_len..]);
             result_tokens.push(next_token);
             input_tensors = Tensor::<u32>::new(vec![next_token], &vec![1]);
 
-            if next_token == self.eos_token_id {
+            if generation_config.eos_token_id.contains(&next_token) {
                 None
             } else {
                 Some(next_token) // 返回生成的 token
@@ -214,6 +399,15 @@ impl Llama<f32> {
     }
 }
 
+// A live hypothesis of beam search
+struct Beam {
+    tokens: Vec<u32>,    // generated after the prompt
+    score: f32,          // sum of the log-probabilities of tokens
+    cache: KVCache<f32>, // prompt + tokens
+    logprobs: Vec<f32>,  // of the next token
+}
+
+#[allow(clippy::too_many_arguments)]
 fn self_attention(
     hidden_states: &mut Tensor<f32>, // (seq, n_kv_h * n_groups * dqkv)
     att_scores: &mut Tensor<f32>,    // (n_kv_h, n_groups, seq, total_seq)
@@ -255,8 +449,7 @@ fn self_attention(
                     let k_vec = &k_data[k_start_idx..k_start_idx + head_dim];
 
                     // 计算注意力分数 (Q @ K^T / sqrt(d))
-                    let score = q_vec.iter().zip(k_vec).map(|(&q, &k)| q * k).sum::<f32>()
-                        / (head_dim as f32).sqrt();
+                    let score = simd::dot(q_vec, k_vec) / (head_dim as f32).sqrt();
 
                     // 计算得分在 att_scores 中的位置
                     let att_score_idx = k_head * att_idx_3
@@ -288,46 +481,42 @@ fn self_attention(
             for seq_idx in 0..seq_len {
                 let att_vec_start_idx = seq_idx * att_idx_1 + att_head_offset;
 
-                for head_dim_idx in 0..head_dim {
-                    let v_start_idx = head_dim_idx + v_head_offset;
-                    let mut weighted_sum = 0.0;
-
-                    for total_seq_idx in 0..total_seq_len {
-                        let att_idx = att_vec_start_idx + total_seq_idx;
-                        let v_idx = v_start_idx + total_seq_idx * num_kv_heads * head_dim;
+                // 计算 hidden_states 中该头输出的起始位置
+                let hidden_idx = seq_idx * num_kv_heads * query_heads_per_kv_group * head_dim
+                    + v_head * query_heads_per_kv_group * head_dim
+                    + q_group * head_dim;
+                let out = &mut hidden_states_data[hidden_idx..hidden_idx + head_dim];
+                out.fill(0.0);
 
-                        // 计算注意力加权值
-                        weighted_sum += att_scores_data[att_idx] * v_data[v_idx];
-                    }
+                for total_seq_idx in 0..total_seq_len {
+                    let att = att_scores_data[att_vec_start_idx + total_seq_idx];
+                    let v_start_idx = total_seq_idx * num_kv_heads * head_dim + v_head_offset;
 
-                    // 计算 hidden_states 的索引，并存储计算结果
-                    let hidden_idx = seq_idx * num_kv_heads * query_heads_per_kv_group * head_dim
-                        + v_head * query_heads_per_kv_group * head_dim
-                        + q_group * head_dim
-                        + head_dim_idx;
-                    hidden_states_data[hidden_idx] = weighted_sum;
+                    // 计算注意力加权值
+                    simd::axpy(out, att, &v_data[v_start_idx..v_start_idx + head_dim]);
                 }
             }
         }
     }
 }
 
-fn mlp(
+#[allow(clippy::too_many_arguments)]
+fn mlp<T: WeightType, W: WeightMatrix>(
     residual: &mut Tensor<f32>,
     hidden_states: &mut Tensor<f32>,
     gate: &mut Tensor<f32>,
     up: &mut Tensor<f32>,
-    w_up: &Tensor<f32>,
-    w_down: &Tensor<f32>,
-    w_gate: &Tensor<f32>,
-    rms_w: &Tensor<f32>,
+    w_up: &W,
+    w_down: &W,
+    w_gate: &W,
+    rms_w: &Tensor<T>,
     eps: f32,
 ) {
-    OP::rms_norm(hidden_states, &residual, &rms_w, eps);
-    OP::matmul_transb(gate, 0., &hidden_states, &w_gate, 1.);
-    OP::matmul_transb(up, 0., &hidden_states, &w_up, 1.);
-    OP::swiglu(up, &gate);
-    OP::matmul_transb(residual, 1., &up, &w_down, 1.);
+    OP::rms_norm(hidden_states, residual, rms_w, eps);
+    OP::matmul_transb(gate, 0., hidden_states, w_gate, 1.);
+    OP::matmul_transb(up, 0., hidden_states, w_up, 1.);
+    OP::swiglu(up, gate);
+    OP::matmul_transb(residual, 1., up, w_down, 1.);
 }
 
 #[test]
@@ -368,13 +557,28 @@ pub fn test_mlp() {
     ))
 }
 
+#[cfg(test)]
+fn story_model_dir() -> std::path::PathBuf {
+    let project_dir = env!("CARGO_MANIFEST_DIR");
+    std::path::PathBuf::from(project_dir)
+        .join("models")
+        .join("story")
+}
+
+#[cfg(test)]
+fn max_abs_diff(x: &[f32], y: &[f32]) -> f32 {
+    assert_eq!(x.len(), y.len());
+    x.iter()
+        .zip(y)
+        .map(|(a, b)| (a - b).abs())
+        .fold(0f32, f32::max)
+}
+
 #[test]
 pub fn test_load_safetensors() {
     use crate::tensor::float_eq;
-    use std::path::PathBuf;
-    let project_dir = env!("CARGO_MANIFEST_DIR");
-    let model_dir = PathBuf::from(project_dir).join("models").join("story");
-    let model = Llama::from_safetensors(model_dir);
+    let model_dir = story_model_dir();
+    let model = Llama::from_safetensors(model_dir).unwrap();
     assert_eq!(model.vocab, 2048);
     assert_eq!(model.n_layers, 2);
     assert_eq!(model.n_q_h, 8);
@@ -384,13 +588,13 @@ pub fn test_load_safetensors() {
     assert_eq!(model.di, 384);
 
     assert!(float_eq(
-        &model.params.embedding_table.data()[50],
+        &model.params.embedding_table.as_dense().unwrap().data()[50],
         &0.14453125,
         1e-6
     ));
     assert_eq!(
-        model.params.lm_head.data()[10],
-        model.params.embedding_table.data()[10]
+        model.params.lm_head.as_dense().unwrap().data()[10],
+        model.params.embedding_table.as_dense().unwrap().data()[10]
     );
     assert!(float_eq(
         &model.params.rms_att_w[0].data()[10],
@@ -408,30 +612,432 @@ pub fn test_load_safetensors() {
         1e-6
     ));
     assert!(float_eq(
-        &model.params.w_down[0].data()[100],
+        &model.params.w_down[0].as_dense().unwrap().data()[100],
         &-0.0625,
         1e-6
     ));
-    assert!(float_eq(&model.params.w_up[0].data()[100], &1.46875, 1e-6));
     assert!(float_eq(
-        &model.params.w_gate[1].data()[100],
+        &model.params.w_up[0].as_dense().unwrap().data()[100],
+        &1.46875,
+        1e-6
+    ));
+    assert!(float_eq(
+        &model.params.w_gate[1].as_dense().unwrap().data()[100],
         &0.296875,
         1e-6
     ));
     assert!(float_eq(
-        &model.params.wq[1].data()[100],
+        &model.params.wq[1].as_dense().unwrap().data()[100],
         &0.032226563,
         1e-6
     ));
     assert!(float_eq(
-        &model.params.wk[1].data()[100],
+        &model.params.wk[1].as_dense().unwrap().data()[100],
         &-0.21386719,
         1e-6
     ));
     assert!(float_eq(
-        &model.params.wv[0].data()[100],
+        &model.params.wv[0].as_dense().unwrap().data()[100],
         &0.041015625,
         1e-6
     ));
-    assert!(float_eq(&model.params.wo[0].data()[100], &0.01965332, 1e-6));
+    assert!(float_eq(
+        &model.params.wo[0].as_dense().unwrap().data()[100],
+        &0.01965332,
+        1e-6
+    ));
+}
+
+#[test]
+pub fn test_half_precision_forward() {
+    use half::{bf16, f16};
+    let model_dir = story_model_dir();
+    let input = Tensor::<u32>::new(vec![1, 400, 20, 35, 90], &vec![5]);
+
+    let model = Llama::<f32>::from_safetensors(&model_dir).unwrap();
+    let expected = model.forward(&input, &mut model.new_cache());
+
+    // story 模型的权重本身可以用 bf16 精确表示，半精度结果应与 f32 几乎一致
+    let model = Llama::<f16>::from_safetensors(&model_dir).unwrap();
+    let logits = model.forward(&input, &mut model.new_cache());
+    assert!(max_abs_diff(logits.data(), expected.data()) < 1e-3);
+    let model = Llama::<bf16>::from_safetensors(&model_dir).unwrap();
+    let logits = model.forward(&input, &mut model.new_cache());
+    assert!(max_abs_diff(logits.data(), expected.data()) < 1e-3);
+}
+
+#[test]
+pub fn test_quantized_forward() {
+    let model_dir = story_model_dir();
+    let input = Tensor::<u32>::new(vec![1, 400, 20, 35, 90], &vec![5]);
+    let next = Tensor::<u32>::new(vec![55], &vec![1]);
+
+    let model = Llama::<f32>::from_safetensors(&model_dir).unwrap();
+    let mut cache = model.new_cache();
+    let expected = [
+        model.forward(&input, &mut cache),
+        model.forward(&next, &mut cache),
+    ];
+
+    // 检查 prefill (GEMM) 与 decode (GEMV) 两条路径。q8_0 的 logits 误差在最大 logit 的 3% 以内，
+    // 且 top-1 相同；4-bit 权重误差较大，误差在 35% 以内，且原模型的 top-1 仍在 top-10 中
+    for (spec, max_rel_diff, max_rank) in [
+        ("q8_0", 0.03, 0),
+        ("attention=q4_0,ffn=q4_0,lm_head=q8_0", 0.35, 9),
+        (
+            "attention=q4_1,ffn=q4_1,lm_head=q8_0,embedding=q8_0",
+            0.35,
+            9,
+        ),
+    ] {
+        let quant = QuantConfig::parse(spec).unwrap();
+        let model = Llama::<f32>::from_safetensors_quantized(&model_dir, &quant).unwrap();
+        assert!(model.params.wq[0].as_dense().is_none());
+        assert!(model.params.w_up[0].as_dense().is_none());
+        let mut cache = model.new_cache();
+        let logits = [
+            model.forward(&input, &mut cache),
+            model.forward(&next, &mut cache),
+        ];
+        for (x, y) in logits.iter().zip(&expected) {
+            let max_diff = max_abs_diff(x.data(), y.data());
+            let scale = y.data().iter().fold(0f32, |m, v| m.max(v.abs()));
+            assert!(
+                max_diff < max_rel_diff * scale,
+                "{spec}: max logit difference {max_diff}"
+            );
+            let top1 = (0..y.size())
+                .max_by(|&a, &b| y.data()[a].total_cmp(&y.data()[b]))
+                .unwrap();
+            let rank = x.data().iter().filter(|&&v| v > x.data()[top1]).count();
+            assert!(rank <= max_rank, "{spec}: top-1 token ranked {rank}");
+        }
+    }
+}
+
+#[test]
+pub fn test_load_errors() {
+    use safetensors::tensor::TensorView;
+    use safetensors::{Dtype, SafeTensors};
+    let model_dir = story_model_dir();
+
+    let err = Llama::<f32>::from_safetensors(model_dir.join("missing")).err();
+    assert!(matches!(err, Some(LoadError::Io { path, .. }) if path.ends_with("config.json")));
+
+    // 复制 story 模型，删去或替换 model.norm.weight
+    let config = LlamaConfigJson::from_model_dir(&model_dir).unwrap();
+    let bytes = std::fs::read(model_dir.join("model.safetensors")).unwrap();
+    let safetensor = SafeTensors::deserialize(&bytes).unwrap();
+    let broken_dir = std::env::temp_dir().join(format!("broken-{}", std::process::id()));
+    std::fs::create_dir_all(&broken_dir).unwrap();
+    std::fs::copy(
+        model_dir.join("config.json"),
+        broken_dir.join("config.json"),
+    )
+    .unwrap();
+    let load_with_norm = |norm: Option<TensorView>| {
+        let mut tensors = safetensor.tensors();
+        tensors.retain(|(name, _)| name != "model.norm.weight");
+        tensors.extend(norm.map(|t| ("model.norm.weight".to_string(), t)));
+        safetensors::serialize_to_file(tensors, &None, &broken_dir.join("model.safetensors"))
+            .unwrap();
+        Llama::<f32>::from_safetensors(&broken_dir).err()
+    };
+
+    let d = config.hidden_size;
+    let norm = vec![0u8; d * 4];
+    let missing = load_with_norm(None);
+    let wrong_shape = load_with_norm(Some(
+        TensorView::new(Dtype::F32, vec![d / 2, 2], &norm).unwrap(),
+    ));
+    let wrong_dtype = load_with_norm(Some(TensorView::new(Dtype::I32, vec![d], &norm).unwrap()));
+    std::fs::remove_dir_all(&broken_dir).unwrap();
+
+    assert!(matches!(
+        missing,
+        Some(LoadError::ShapeMismatch(mismatches))
+            if mismatches.len() == 1
+                && mismatches[0].name == "model.norm.weight"
+                && mismatches[0].actual.is_none()
+    ));
+    assert!(matches!(
+        wrong_shape,
+        Some(LoadError::ShapeMismatch(mismatches))
+            if mismatches.len() == 1
+                && mismatches[0].name == "model.norm.weight"
+                && mismatches[0].expected == [d]
+                && mismatches[0].actual == Some(vec![d / 2, 2])
+    ));
+    assert!(matches!(
+        wrong_dtype,
+        Some(LoadError::DtypeMismatch { name, dtype: Dtype::I32 }) if name == "model.norm.weight"
+    ));
+}
+
+#[test]
+pub fn test_torch_dtype_hint() {
+    let model_dir = story_model_dir();
+    let input = Tensor::<u32>::new(vec![1, 400, 20, 35, 90], &vec![5]);
+    let model = Llama::<f32>::from_safetensors(&model_dir).unwrap();
+    let expected = model.forward(&input, &mut model.new_cache());
+
+    // torch_dtype 只是精度提示：未知或缺省时 f32 的 tensor 照常加载
+    let mut json: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(model_dir.join("config.json")).unwrap())
+            .unwrap();
+    let hint_dir = std::env::temp_dir().join(format!("dtype-hint-{}", std::process::id()));
+    std::fs::create_dir_all(&hint_dir).unwrap();
+    std::fs::copy(
+        model_dir.join("model.safetensors"),
+        hint_dir.join("model.safetensors"),
+    )
+    .unwrap();
+    let mut load = |torch_dtype: Option<&str>| {
+        match torch_dtype {
+            Some(dtype) => json["torch_dtype"] = dtype.into(),
+            None => _ = json.as_object_mut().unwrap().remove("torch_dtype"),
+        }
+        std::fs::write(hint_dir.join("config.json"), json.to_string()).unwrap();
+        Llama::<f32>::from_safetensors(&hint_dir)
+    };
+    let models = [load(Some("float64")), load(None)];
+    std::fs::remove_dir_all(&hint_dir).unwrap();
+
+    for model in models {
+        let model = model.unwrap();
+        let logits = model.forward(&input, &mut model.new_cache());
+        assert_eq!(logits.data(), expected.data());
+    }
+}
+
+#[test]
+pub fn test_tied_embeddings() {
+    use safetensors::SafeTensors;
+    let model_dir = story_model_dir();
+    let input = Tensor::<u32>::new(vec![1, 400, 20, 35, 90], &vec![5]);
+    let shared = |model: &Llama<f32>| {
+        let embedding = model.params.embedding_table.as_dense().unwrap();
+        let lm_head = model.params.lm_head.as_dense().unwrap();
+        std::ptr::eq(embedding.data(), lm_head.data())
+    };
+
+    // story 模型只保存了 lm_head.weight
+    let model = Llama::<f32>::from_safetensors(&model_dir).unwrap();
+    assert!(shared(&model));
+    let expected = model.forward(&input, &mut model.new_cache());
+
+    // 改为只保存 model.embed_tokens.weight
+    let bytes = std::fs::read(model_dir.join("model.safetensors")).unwrap();
+    let safetensor = SafeTensors::deserialize(&bytes).unwrap();
+    let tensors = safetensor.tensors().into_iter().map(|(name, t)| {
+        let name = if name == "lm_head.weight" {
+            "model.embed_tokens.weight".to_string()
+        } else {
+            name
+        };
+        (name, t)
+    });
+    let tied_dir = std::env::temp_dir().join(format!("tied-{}", std::process::id()));
+    std::fs::create_dir_all(&tied_dir).unwrap();
+    std::fs::copy(model_dir.join("config.json"), tied_dir.join("config.json")).unwrap();
+    safetensors::serialize_to_file(tensors, &None, &tied_dir.join("model.safetensors")).unwrap();
+    let model = Llama::<f32>::from_safetensors(&tied_dir).unwrap();
+    std::fs::remove_dir_all(&tied_dir).unwrap();
+
+    assert!(shared(&model));
+    let logits = model.forward(&input, &mut model.new_cache());
+    assert_eq!(logits.data(), expected.data());
+}
+
+#[test]
+pub fn test_generate_stop_criteria() {
+    let model_dir = story_model_dir();
+    let model = Llama::<f32>::from_safetensors(&model_dir).unwrap();
+    let prompt = [1, 400, 20, 35, 90];
+
+    // top_k = 1 即贪心解码，结果确定
+    let greedy = model
+        .generation_config()
+        .clone()
+        .top_k(1)
+        .eos_token_ids(vec![])
+        .max_new_tokens(12);
+    let output = model.generate(&prompt, &greedy);
+    assert_eq!(output.len(), prompt.len() + 12);
+    assert_eq!(output[..prompt.len()], prompt);
+    let generated = &output[prompt.len()..];
+    let mut kvcache = model.new_cache();
+    let streamed = model
+        .stream_generate(&prompt, &greedy, &mut kvcache)
+        .collect::<Vec<_>>();
+    assert_eq!(streamed, generated);
+    // max_new_tokens 不计入 prompt
+    assert_eq!(kvcache.len(), prompt.len() + 11);
+
+    // 停止序列的 tokens 会被返回
+    let stop = greedy.clone().stop_sequence(generated[3..5].to_vec());
+    let output = model.generate(&prompt, &stop);
+    assert_eq!(output[prompt.len()..], generated[..5]);
+
+    // EOS 结束 generate 的输出，但不会被 stream_generate 返回
+    let eos = greedy.clone().eos_token_ids(vec![generated[6]]);
+    let output = model.generate(&prompt, &eos);
+    assert_eq!(output[prompt.len()..], generated[..7]);
+    let mut kvcache = model.new_cache();
+    let streamed = model
+        .stream_generate(&prompt, &eos, &mut kvcache)
+        .collect::<Vec<_>>();
+    assert_eq!(streamed, generated[..6]);
+}
+
+#[test]
+pub fn test_seeded_generate() {
+    let model_dir = story_model_dir();
+    let model = Llama::<f32>::from_safetensors(&model_dir).unwrap();
+    let prompt = [1, 400, 20, 35, 90];
+
+    let config = model
+        .generation_config()
+        .clone()
+        .do_sample(true)
+        .eos_token_ids(vec![])
+        .max_new_tokens(30);
+    let run = |seed| model.generate(&prompt, &config.clone().seed(seed));
+    let first = run(42);
+    assert_eq!(run(42), first);
+    let mut kvcache = model.new_cache();
+    let streamed = model
+        .stream_generate(&prompt, &config.clone().seed(42), &mut kvcache)
+        .collect::<Vec<_>>();
+    assert_eq!(streamed, first[prompt.len()..]);
+    // 不同的种子几乎不可能得到相同的 30 个 token
+    assert_ne!(run(7), first);
+}
+
+#[test]
+pub fn test_beam_search() {
+    let model_dir = story_model_dir();
+    let model = Llama::<f32>::from_safetensors(&model_dir).unwrap();
+    let prompt = [1, 400, 20, 35, 90];
+    let prefill = || {
+        let mut cache = model.new_cache();
+        model.forward(&Tensor::<u32>::new(prompt.to_vec(), &vec![5]), &mut cache);
+        cache
+    };
+
+    // fork 出的 cache 与原 cache 互不影响，结果与各自重新计算一致
+    let mut cache = prefill();
+    let mut forked = cache.fork();
+    let logits = model.forward(&Tensor::<u32>::new(vec![55], &vec![1]), &mut cache);
+    let forked_logits = model.forward(&Tensor::<u32>::new(vec![66], &vec![1]), &mut forked);
+    let expected = model.forward(&Tensor::<u32>::new(vec![55], &vec![1]), &mut prefill());
+    assert!(logits.close_to(&expected, 1e-5));
+    let expected = model.forward(&Tensor::<u32>::new(vec![66], &vec![1]), &mut prefill());
+    assert!(forked_logits.close_to(&expected, 1e-5));
+
+    // 一个 beam 的 beam search 就是贪心解码
+    let greedy = model
+        .generation_config()
+        .clone()
+        .top_k(1)
+        .eos_token_ids(vec![])
+        .max_new_tokens(10);
+    let greedy_output = model.generate(&prompt, &greedy);
+    assert_eq!(model.beam_search(&prompt, &greedy), greedy_output);
+
+    // 多个 beam 找到的序列不比贪心解码的可能性低
+    let sum_logprobs = |output: &[u32]| {
+        let mut cache = model.new_cache();
+        let mut logits = model.forward(&Tensor::<u32>::new(prompt.to_vec(), &vec![5]), &mut cache);
+        let mut total = 0.;
+        for &token in &output[prompt.len()..] {
+            total += sampling::log_softmax(logits.data())[token as usize];
+            logits = model.forward(&Tensor::<u32>::new(vec![token], &vec![1]), &mut cache);
+        }
+        total
+    };
+    let beam_output = model.generate(&prompt, &greedy.clone().num_beams(4));
+    assert_eq!(beam_output.len(), greedy_output.len());
+    assert_eq!(beam_output[..prompt.len()], prompt);
+    assert!(sum_logprobs(&beam_output) >= sum_logprobs(&greedy_output) - 1e-4);
+
+    // 遇到 EOS 的 beam 结束，EOS 只会出现在结果的最后
+    let eos = beam_output[prompt.len() + 3];
+    let output = model.generate(
+        &prompt,
+        &greedy.clone().num_beams(4).eos_token_ids(vec![eos]),
+    );
+    let generated = &output[prompt.len()..];
+    assert!(!generated[..generated.len() - 1].contains(&eos));
+    assert!(generated.last() == Some(&eos) || generated.len() == 10);
+}
+
+#[test]
+pub fn test_generate_logprobs() {
+    let model_dir = story_model_dir();
+    let model = Llama::<f32>::from_safetensors(&model_dir).unwrap();
+    let prompt = [1, 400, 20, 35, 90];
+
+    let greedy = model
+        .generation_config()
+        .clone()
+        .top_k(1)
+        .eos_token_ids(vec![])
+        .max_new_tokens(8)
+        .top_logprobs(3);
+    let (output, logprobs) = model.generate_with_logprobs(&prompt, &greedy);
+    assert_eq!(output, model.generate(&prompt, &greedy));
+    assert_eq!(logprobs.len(), 8);
+    let tokens = logprobs.iter().map(|l| l.token).collect::<Vec<_>>();
+    assert_eq!(tokens, output[prompt.len()..]);
+
+    // 贪心解码选出的就是最可能的候选
+    for l in &logprobs {
+        assert_eq!(l.top_logprobs.len(), 3);
+        assert_eq!(l.top_logprobs[0], (l.token, l.logprob));
+        assert!(l.top_logprobs.windows(2).all(|w| w[0].1 >= w[1].1));
+        assert!(l.top_logprobs.iter().map(|(_, p)| p.exp()).sum::<f32>() <= 1. + 1e-5);
+    }
+    let logits = model.forward(
+        &Tensor::<u32>::new(prompt.to_vec(), &vec![5]),
+        &mut model.new_cache(),
+    );
+    let expected = sampling::log_softmax(logits.data())[output[prompt.len()] as usize];
+    assert!((logprobs[0].logprob - expected).abs() < 1e-5);
+
+    // logprob 不受 temperature 影响
+    let (_, hot) = model.generate_with_logprobs(
+        &prompt,
+        &greedy
+            .clone()
+            .do_sample(true)
+            .top_k(0)
+            .temperature(2.)
+            .seed(1),
+    );
+    assert!((hot[0].top_logprobs[0].1 - logprobs[0].logprob).abs() < 1e-5);
+}
+
+#[test]
+pub fn test_forward_all() {
+    let model_dir = story_model_dir();
+    let model = Llama::<f32>::from_safetensors(&model_dir).unwrap();
+    let tokens = [1, 400, 20, 35, 90];
+
+    let mut cache = model.new_cache();
+    let logits = model.forward_all(&Tensor::<u32>::new(tokens.to_vec(), &vec![5]), &mut cache);
+    assert_eq!(logits.shape(), &vec![5, model.vocab]);
+    assert_eq!(cache.len(), 5);
+
+    // 第 i 行与只输入前 i + 1 个 token 时 forward 的结果一致
+    for i in 0..tokens.len() {
+        let input = Tensor::<u32>::new(tokens[..=i].to_vec(), &vec![i + 1]);
+        let expected = model.forward(&input, &mut model.new_cache());
+        let row = &logits.data()[i * model.vocab..(i + 1) * model.vocab];
+        let max_diff = max_abs_diff(row, expected.data());
+        assert!(
+            max_diff < 1e-4,
+            "position {i}: max logit difference {max_diff}"
+        );
+    }
 }
@@ -1,16 +1,61 @@
+use crate::dtype::WeightType;
+use crate::gemm;
+use crate::simd;
 use crate::tensor::Tensor;
+use crate::weight::WeightMatrix;
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use std::sync::{Arc, RwLock};
+
+// Worker pool shared by the parallel operators; built lazily with one thread per core
+static THREAD_POOL: RwLock<Option<Arc<ThreadPool>>> = RwLock::new(None);
+
+// Below this many multiply-adds a single-row matmul (GEMV) runs on the calling thread;
+// the blocked GEMM already runs one-block products on the calling thread
+const PARALLEL_MIN_WORK: usize = 1 << 15;
+
+// Set the number of worker threads used by the parallel operators.
+// `0` means one thread per available core.
+pub fn set_num_threads(n_threads: usize) {
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(n_threads)
+        .thread_name(|i| format!("lm-worker-{i}"))
+        .build()
+        .expect("failed to build operator thread pool");
+    *THREAD_POOL.write().unwrap() = Some(Arc::new(pool));
+}
+
+// Number of worker threads used by the parallel operators
+#[allow(unused)]
+pub fn num_threads() -> usize {
+    thread_pool().current_num_threads()
+}
+
+fn thread_pool() -> Arc<ThreadPool> {
+    if let Some(pool) = THREAD_POOL.read().unwrap().as_ref() {
+        return pool.clone();
+    }
+    let mut guard = THREAD_POOL.write().unwrap();
+    guard
+        .get_or_insert_with(|| {
+            Arc::new(
+                ThreadPoolBuilder::new()
+                    .thread_name(|i| format!("lm-worker-{i}"))
+                    .build()
+                    .expect("failed to build operator thread pool"),
+            )
+        })
+        .clone()
+}
 
 // get (row) vectors from a 2D table given a list of indices
-pub fn gather(y: &mut Tensor<f32>, indices: &Tensor<u32>, table: &Tensor<f32>) {
+pub fn gather<B: WeightMatrix + ?Sized>(y: &mut Tensor<f32>, indices: &Tensor<u32>, table: &B) {
     let length = indices.size();
-    let table_shape = table.shape();
-    assert!(table_shape.len() == 2);
-    let dim = table_shape[1];
+    let dim = table.cols();
     assert!(y.size() == length * dim);
     for i in 0..length {
-        let src = &table.data()[indices.data()[i] as usize * dim..][..dim];
         let dst = &mut unsafe { y.data_mut() }[i * dim..][..dim];
-        dst.copy_from_slice(src);
+        table.row_to_f32(indices.data()[i] as usize, 0, dst);
     }
 }
 
@@ -70,7 +115,7 @@ pub fn masked_softmax(y: &mut Tensor<f32>) {
     }
 }
 
-pub fn rms_norm(y: &mut Tensor<f32>, x: &Tensor<f32>, w: &Tensor<f32>, epsilon: f32) {
+pub fn rms_norm<W: WeightType>(y: &mut Tensor<f32>, x: &Tensor<f32>, w: &Tensor<W>, epsilon: f32) {
     let x_shape = x.shape();
     let w_shape = w.shape();
     assert_eq!(
@@ -83,25 +128,18 @@ pub fn rms_norm(y: &mut Tensor<f32>, x: &Tensor<f32>, w: &Tensor<f32>, epsilon:
     let batch_size = x_shape[..x_shape.len() - 1].iter().product::<usize>(); // 计算批次大小
     let x_data = x.data();
     let y_data = unsafe { y.data_mut() };
-    let w_data = w.data();
+    let w_data = W::as_f32(w.data());
 
     for batch in 0..batch_size {
         let offset = batch * dim;
 
+        let x_vec = &x_data[offset..offset + dim];
+
         // 计算 RMS 值
-        let rms = (x_data[offset..offset + dim]
-            .iter()
-            .map(|&xi| xi * xi)
-            .sum::<f32>()
-            / dim as f32
-            + epsilon)
-            .sqrt();
+        let rms = (simd::dot(x_vec, x_vec) / dim as f32 + epsilon).sqrt();
 
         // 归一化并应用权重
-        for i in 0..dim {
-            let x_norm = x_data[offset + i] / rms;
-            y_data[offset + i] = w_data[i] * x_norm;
-        }
+        simd::scale_mul(&mut y_data[offset..offset + dim], 1.0 / rms, &w_data, x_vec);
     }
 }
 
@@ -113,17 +151,22 @@ pub fn swiglu(y: &mut Tensor<f32>, x: &Tensor<f32>) {
 
     let _y = unsafe { y.data_mut() };
     let _x = x.data();
-    for i in 0..len {
-        let silu_x = _x[i] / (1.0 + (-_x[i]).exp());
-        _y[i] *= silu_x;
-    }
+    simd::swiglu(_y, _x);
 }
 
 // C = beta * C + alpha * A @ B^T
 // hint: You don't need to do an explicit transpose of B
-pub fn matmul_transb(c: &mut Tensor<f32>, beta: f32, a: &Tensor<f32>, b: &Tensor<f32>, alpha: f32) {
+// Multi-row inputs (prefill) go through the cache-blocked GEMM in `gemm`; a single row
+// (decoding, and the lm_head projection) is dispatched to `gemv_transb`.
+pub fn matmul_transb<B: WeightMatrix + ?Sized>(
+    c: &mut Tensor<f32>,
+    beta: f32,
+    a: &Tensor<f32>,
+    b: &B,
+    alpha: f32,
+) {
     let (a_row, a_col) = (a.shape()[0], a.shape()[1]);
-    let (b_row, b_col) = (b.shape()[0], b.shape()[1]);
+    let (b_row, b_col) = (b.rows(), b.cols());
     let (c_row, c_col) = (c.shape()[0], c.shape()[1]);
 
     assert!(a_col == b_col, "Inner dimensions of A and B must match");
@@ -132,19 +175,70 @@ pub fn matmul_transb(c: &mut Tensor<f32>, beta: f32, a: &Tensor<f32>, b: &Tensor
         "Output matrix C must have shape (a_row, b_row)"
     );
 
-    let c_data = unsafe { c.data_mut() };
-    let a_data = a.data();
-    let b_data = b.data();
+    if c_row == 1 {
+        gemv_transb(c, beta, a, b, alpha);
+        return;
+    }
 
-    for i in 0..c_row {
-        for j in 0..c_col {
-            let mut sum = 0.0;
-            for k in 0..a_col {
-                sum = sum + a_data[i * a_col + k] * b_data[j * b_col + k];
+    let pool = thread_pool();
+    gemm::gemm_transb(&pool, c_row, beta, a.data(), b, alpha, unsafe {
+        c.data_mut()
+    });
+}
+
+// y = beta * y + alpha * x @ W^T, x: (1, k), W: (n, k), y: (1, n)
+// Decoding is bound by the bandwidth of streaming W, so every weight is read exactly once:
+// each worker owns a contiguous block of rows of W and walks it four rows at a time,
+// sharing the loads of x between them.
+pub fn gemv_transb<B: WeightMatrix + ?Sized>(
+    y: &mut Tensor<f32>,
+    beta: f32,
+    x: &Tensor<f32>,
+    w: &B,
+    alpha: f32,
+) {
+    let k = *x.shape().last().unwrap();
+    let (n, w_col) = (w.rows(), w.cols());
+    assert!(x.size() == k, "x must be a single row");
+    assert!(k == w_col, "Inner dimensions of x and W must match");
+    assert!(y.size() == n, "Output y must have one element per row of W");
+
+    let x_data = x.data();
+    let y_data = unsafe { y.data_mut() };
+
+    // y[j0..j0 + block.len()] = beta * y + alpha * x @ W[j0..]^T
+    let compute_block = |(j0, block): (usize, &mut [f32])| {
+        let mut quads = block.chunks_exact_mut(4);
+        let mut j = j0;
+        for quad in &mut quads {
+            let sums = w.row_dot4(j, x_data);
+            for (y_val, sum) in quad.iter_mut().zip(sums) {
+                *y_val = beta * *y_val + alpha * sum;
             }
-            c_data[i * c_col + j] = beta * c_data[i * c_col + j] + alpha * sum;
+            j += 4;
+        }
+        for (y_val, j) in quads.into_remainder().iter_mut().zip(j..) {
+            *y_val = beta * *y_val + alpha * w.row_dot(j, x_data);
         }
+    };
+
+    if n * k < PARALLEL_MIN_WORK {
+        compute_block((0, y_data));
+        return;
     }
+
+    let pool = thread_pool();
+    // A few row blocks per worker to even out the load, each a multiple of four rows
+    let block_rows = n
+        .div_ceil(pool.current_num_threads() * 4)
+        .next_multiple_of(4)
+        .max(16);
+    pool.install(|| {
+        y_data
+            .par_chunks_mut(block_rows)
+            .enumerate()
+            .for_each(|(jb, block)| compute_block((jb * block_rows, block)))
+    });
 }
 
 // Dot product of two tensors (treated as vectors)
@@ -152,78 +246,7 @@ pub fn matmul_transb(c: &mut Tensor<f32>, beta: f32, a: &Tensor<f32>, b: &Tensor
 pub fn dot(x: &Tensor<f32>, y: &Tensor<f32>) -> f32 {
     let len = x.size();
     assert!(len == y.size());
-    let x_ = x.data();
-    let y_ = y.data();
-    let mut sum = 0.0;
-    for i in 0..len {
-        sum += x_[i] * y_[i];
-    }
-    sum
-}
-
-// Sample a index from a tensor (treated as a probability vector)
-pub fn random_sample(x: &Tensor<f32>, top_p: f32, top_k: u32, temperature: f32) -> u32 {
-    assert!(x.shape()[x.shape().len() - 1] == x.size());
-    if temperature <= 0. || top_k < 2 || top_p <= 0. {
-        return x
-            .data()
-            .iter()
-            .enumerate()
-            .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap())
-            .unwrap()
-            .0 as _;
-    }
-
-    #[derive(Clone, Copy, PartialEq, Debug)]
-    struct Probability {
-        val: f32,
-        tok: u32,
-    }
-    impl Eq for Probability {}
-    impl PartialOrd for Probability {
-        #[inline]
-        fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-            Some(self.cmp(other))
-        }
-    }
-    impl Ord for Probability {
-        #[inline]
-        fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-            match self.val.total_cmp(&other.val) {
-                std::cmp::Ordering::Equal => self.tok.cmp(&other.tok),
-                ord => ord.reverse(),
-            }
-        }
-    }
-    impl From<(usize, &f32)> for Probability {
-        #[inline]
-        fn from((i, p): (usize, &f32)) -> Self {
-            Self {
-                val: p.clone(),
-                tok: i as _,
-            }
-        }
-    }
-
-    // sort
-    let mut logits = x
-        .data()
-        .iter()
-        .enumerate()
-        .map(Probability::from)
-        .collect::<Vec<_>>();
-    logits.sort_unstable();
-    let max = core::mem::replace(&mut logits[0].val, 1.);
-    // softmax & sum
-    for i in 1..logits.len() {
-        logits[i].val = logits[i - 1].val + ((logits[i].val - max) / temperature).exp();
-    }
-    // topk & topp & random
-    let pk = logits[(top_k as usize).min(logits.len()) - 1].val;
-    let pp = logits[logits.len() - 1].val * top_p;
-    let plimit = rand::random::<f32>() * f32::min(pk, pp);
-    // sample
-    logits.iter().find(|p| p.val >= plimit).unwrap().tok
+    simd::dot(x.data(), y.data())
 }
 
 // Your implementation should at least pass the following tests:
@@ -264,3 +287,63 @@ fn test_matmul_transb() {
         1e-3
     ));
 }
+
+#[test]
+fn test_gemv_transb() {
+    set_num_threads(3);
+    for (n, k) in [(3, 5), (37, 64), (1030, 96)] {
+        let x = Tensor::<f32>::new(
+            (0..k).map(|x| (x % 17) as f32 * 0.125 - 1.0).collect(),
+            &vec![1, k],
+        );
+        let w = Tensor::<f32>::new(
+            (0..n * k).map(|x| (x % 13) as f32 * 0.25 - 1.5).collect(),
+            &vec![n, k],
+        );
+        let init = (0..n).map(|x| (x % 5) as f32).collect::<Vec<_>>();
+        let mut y = Tensor::<f32>::new(init.clone(), &vec![1, n]);
+        gemv_transb(&mut y, 0.5, &x, &w, 2.);
+
+        let expected = (0..n)
+            .map(|j| 0.5 * init[j] + 2. * simd::scalar::dot(x.data(), &w.data()[j * k..][..k]))
+            .collect();
+        assert!(y.close_to(&Tensor::<f32>::new(expected, &vec![1, n]), 1e-6));
+    }
+}
+
+#[test]
+fn test_matmul_transb_parallel() {
+    use crate::tensor::float_eq;
+    set_num_threads(3);
+    assert_eq!(num_threads(), 3);
+    for (m, n, k) in [(1, 1000, 64), (7, 129, 48), (33, 65, 130)] {
+        let a = Tensor::<f32>::new(
+            (0..m * k).map(|x| (x % 17) as f32 * 0.1 - 0.8).collect(),
+            &vec![m, k],
+        );
+        let b = Tensor::<f32>::new(
+            (0..n * k).map(|x| (x % 13) as f32 * 0.1 - 0.6).collect(),
+            &vec![n, k],
+        );
+        let init = (0..m * n).map(|x| (x % 5) as f32).collect::<Vec<_>>();
+        let mut c = Tensor::<f32>::new(init.clone(), &vec![m, n]);
+        matmul_transb(&mut c, 0.5, &a, &b, 2.);
+
+        let mut expected = init;
+        for i in 0..m {
+            for j in 0..n {
+                let sum = (0..k)
+                    .map(|l| a.data()[i * k + l] * b.data()[j * k + l])
+                    .sum::<f32>();
+                expected[i * n + j] = 0.5 * expected[i * n + j] + 2. * sum;
+            }
+        }
+        // SIMD 与多线程改变了累加顺序，结果只在误差范围内相等；
+        // 恰好抵消为 0 的元素用绝对误差比较
+        assert!(c
+            .data()
+            .iter()
+            .zip(&expected)
+            .all(|(x, y)| float_eq(x, y, 1e-4) || (x - y).abs() < 1e-5));
+    }
+}
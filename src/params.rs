@@ -1,76 +1,288 @@
+use crate::checkpoint::Checkpoint;
 use crate::config::LlamaConfigJson;
+use crate::dtype::WeightType;
+use crate::error::{LoadError, ShapeMismatch};
+use crate::quant::{QuantConfig, QuantType};
 use crate::tensor::Tensor;
-use safetensors::{Dtype, SafeTensors};
+use crate::weight::Weight;
+use half::{bf16, f16};
+use safetensors::tensor::TensorView;
+use safetensors::Dtype;
 pub struct LLamaParams<T> {
     // token_id to embedding lookup table
-    pub embedding_table: Tensor<T>, // (vocab_size, dim)
+    pub embedding_table: Weight<T>, // (vocab_size, dim)
     // decoder layer
     pub rms_att_w: Vec<Tensor<T>>, // (hidden_size, ) x layers
-    pub wq: Vec<Tensor<T>>,        // (n_heads * head_size, hidden_size) x layers
-    pub wk: Vec<Tensor<T>>,        // (n_kv_heads * head_size, hidden_size) x layers
-    pub wv: Vec<Tensor<T>>,        // (n_kv_heads * head_size, hidden_size) x layers
-    pub wo: Vec<Tensor<T>>,        // (hidden_size, n_heads * head_size) x layers
+    pub wq: Vec<Weight<T>>,        // (n_heads * head_size, hidden_size) x layers
+    pub wk: Vec<Weight<T>>,        // (n_kv_heads * head_size, hidden_size) x layers
+    pub wv: Vec<Weight<T>>,        // (n_kv_heads * head_size, hidden_size) x layers
+    pub wo: Vec<Weight<T>>,        // (hidden_size, n_heads * head_size) x layers
     // ffn layer
     pub rms_ffn_w: Vec<Tensor<T>>, // (hidden_size, ) x layers
-    pub w_up: Vec<Tensor<T>>,      // (intermediate_size, hidden_size) x layers
-    pub w_gate: Vec<Tensor<T>>,    // (intermediate_size, hidden_size) x layers
-    pub w_down: Vec<Tensor<T>>,    // (hidden_size, intermediate_size) x layers
+    pub w_up: Vec<Weight<T>>,      // (intermediate_size, hidden_size) x layers
+    pub w_gate: Vec<Weight<T>>,    // (intermediate_size, hidden_size) x layers
+    pub w_down: Vec<Weight<T>>,    // (hidden_size, intermediate_size) x layers
     // output
     pub rms_out_w: Tensor<T>, // (hidden_size, )
-    pub lm_head: Tensor<T>,   // (vocab_size, dim)
+    pub lm_head: Weight<T>,   // (vocab_size, dim)
 }
 
-impl LLamaParams<f32> {
-    pub fn from_safetensors(safetensor: &SafeTensors, config: &LlamaConfigJson) -> Self {
-        let get_tensor = |name: &str| -> Tensor<f32> {
-            // 根据名称获取 tensor
-            let tensor_view = safetensor
-                .tensor(name)
-                .expect(&format!("Tensor {} not found", name));
+// 将 F32 / F16 / BF16 的原始字节转换为权重类型 T 并创建 Tensor
+// 经由 f32 转换，源类型与 T 相同时不损失精度
+fn convert_tensor<T: WeightType>(
+    name: &str,
+    tensor_view: &TensorView,
+) -> Result<Tensor<T>, LoadError> {
+    let bytes = tensor_view.data();
+    let data = match tensor_view.dtype() {
+        Dtype::F32 => bytes
+            .chunks_exact(4)
+            .map(|b| T::from_f32(f32::from_le_bytes(b.try_into().unwrap())))
+            .collect(),
+        Dtype::F16 => bytes
+            .chunks_exact(2)
+            .map(|b| T::from_f32(f16::from_le_bytes(b.try_into().unwrap()).to_f32()))
+            .collect(),
+        Dtype::BF16 => bytes
+            .chunks_exact(2)
+            .map(|b| T::from_f32(bf16::from_le_bytes(b.try_into().unwrap()).to_f32()))
+            .collect(),
+        dtype => {
+            return Err(LoadError::DtypeMismatch {
+                name: name.to_string(),
+                dtype,
+            })
+        }
+    };
+    Ok(Tensor::new(data, &tensor_view.shape().to_vec()))
+}
+
+const EMBED_TOKENS: &str = "model.embed_tokens.weight";
+const LM_HEAD: &str = "lm_head.weight";
+
+// embedding_table 的来源。共享词表的模型通常只保存 model.embed_tokens.weight，
+// 也有只保存 lm_head.weight 的，使用存在的那一个
+fn embedding_name(checkpoint: &Checkpoint, config: &LlamaConfigJson) -> &'static str {
+    if config.tie_word_embeddings && checkpoint.tensor(EMBED_TOKENS).is_none() {
+        LM_HEAD
+    } else {
+        EMBED_TOKENS
+    }
+}
+
+// 按 config 计算每个参数应有的形状，与 LLamaParams 中注释的形状一致
+fn expected_shapes(config: &LlamaConfigJson, embedding_name: &str) -> Vec<(String, Vec<usize>)> {
+    let d = config.hidden_size;
+    let di = config.intermediate_size;
+    let dqkv = d / config.num_attention_heads;
+    let q_dim = config.num_attention_heads * dqkv;
+    let kv_dim = config.num_key_value_heads * dqkv;
 
-            assert_eq!(
-                tensor_view.dtype(),
-                Dtype::F32,
-                "Expected tensor {} to have dtype F32, but found {:?}",
+    let mut shapes = vec![(embedding_name.to_string(), vec![config.vocab_size, d])];
+    for layer_idx in 0..config.num_hidden_layers {
+        let layer = [
+            ("input_layernorm.weight", vec![d]),
+            ("self_attn.q_proj.weight", vec![q_dim, d]),
+            ("self_attn.k_proj.weight", vec![kv_dim, d]),
+            ("self_attn.v_proj.weight", vec![kv_dim, d]),
+            ("self_attn.o_proj.weight", vec![d, q_dim]),
+            ("post_attention_layernorm.weight", vec![d]),
+            ("mlp.up_proj.weight", vec![di, d]),
+            ("mlp.gate_proj.weight", vec![di, d]),
+            ("mlp.down_proj.weight", vec![d, di]),
+        ];
+        shapes
+            .extend(layer.map(|(name, shape)| (format!("model.layers.{layer_idx}.{name}"), shape)));
+    }
+    shapes.push(("model.norm.weight".to_string(), vec![d]));
+    if !config.tie_word_embeddings {
+        shapes.push((LM_HEAD.to_string(), vec![config.vocab_size, d]));
+    }
+    shapes
+}
+
+// 在读取任何数据之前检查所有参数的形状，一次报告全部缺失或形状不匹配的参数
+fn validate_shapes(checkpoint: &Checkpoint, config: &LlamaConfigJson) -> Result<(), LoadError> {
+    let mut mismatches = vec![];
+    for (name, expected) in expected_shapes(config, embedding_name(checkpoint, config)) {
+        let actual = checkpoint.tensor(&name).map(|view| view.shape().to_vec());
+        if actual.as_ref() != Some(&expected) {
+            mismatches.push(ShapeMismatch {
                 name,
-                tensor_view.dtype()
-            );
+                expected,
+                actual,
+            });
+        }
+    }
+    if mismatches.is_empty() {
+        Ok(())
+    } else {
+        Err(LoadError::ShapeMismatch(mismatches))
+    }
+}
 
-            // 将原始字节转换为 f32 类型并创建 Tensor
-            let data = tensor_view
-                .data()
-                .chunks(4)
-                .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
-                .collect();
+impl<T: WeightType> LLamaParams<T> {
+    // embedding_table、注意力、MLP 与 lm_head 四类矩阵按 quant 中各自的格式在加载时量化
+    pub fn from_safetensors(
+        checkpoint: &Checkpoint,
+        config: &LlamaConfigJson,
+        quant: &QuantConfig,
+    ) -> Result<Self, LoadError> {
+        validate_shapes(checkpoint, config)?;
 
-            Tensor::new(data, &tensor_view.shape().to_vec())
+        // 根据名称获取 tensor，validate_shapes 已经检查过它存在且形状正确
+        let get_view = |name: &str| -> TensorView {
+            checkpoint
+                .tensor(name)
+                .expect("tensor presence is checked by validate_shapes")
+        };
+        let get_tensor = |name: &str| -> Result<Tensor<T>, LoadError> {
+            let tensor_view = get_view(name);
+            // 精度与对齐满足时直接借用映射的文件内容，否则转换并复制
+            match checkpoint.borrow_tensor(name) {
+                Some(tensor) => Ok(tensor),
+                None => convert_tensor(name, &tensor_view),
+            }
+        };
+
+        // 矩阵：需要量化时直接从 f32 量化，不经过存储类型 T
+        let get_weight = |name: &str, quant: Option<QuantType>| -> Result<Weight<T>, LoadError> {
+            let Some(quant) = quant else {
+                return Ok(Weight::Dense(get_tensor(name)?));
+            };
+            let tensor_view = get_view(name);
+            let cols = tensor_view.shape()[1];
+            if !cols.is_multiple_of(quant.block_size()) {
+                return Err(LoadError::UnsupportedQuantization {
+                    name: name.to_string(),
+                    quant,
+                    cols,
+                });
+            }
+            Ok(Weight::quantize(
+                &convert_tensor::<f32>(name, &tensor_view)?,
+                quant,
+            ))
         };
 
         let n_layers = config.num_hidden_layers;
 
-        let get_layer_tensors = |prefix: &str| -> Vec<Tensor<f32>> {
+        let get_layer_tensors = |prefix: &str| {
             (0..n_layers)
                 .map(|layer_idx| get_tensor(&format!("model.layers.{layer_idx}.{}", prefix)))
-                .collect()
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let get_layer_weights = |prefix: &str, quant: Option<QuantType>| {
+            (0..n_layers)
+                .map(|layer_idx| get_weight(&format!("model.layers.{layer_idx}.{}", prefix), quant))
+                .collect::<Result<Vec<_>, _>>()
         };
 
-        LLamaParams {
-            embedding_table: if config.tie_word_embeddings {
-                get_tensor("lm_head.weight")
-            } else {
-                get_tensor("model.embed_tokens.weight")
-            },
-            rms_att_w: get_layer_tensors("input_layernorm.weight"),
-            wq: get_layer_tensors("self_attn.q_proj.weight"),
-            wk: get_layer_tensors("self_attn.k_proj.weight"),
-            wv: get_layer_tensors("self_attn.v_proj.weight"),
-            wo: get_layer_tensors("self_attn.o_proj.weight"),
-            rms_ffn_w: get_layer_tensors("post_attention_layernorm.weight"),
-            w_up: get_layer_tensors("mlp.up_proj.weight"),
-            w_gate: get_layer_tensors("mlp.gate_proj.weight"),
-            w_down: get_layer_tensors("mlp.down_proj.weight"),
-            rms_out_w: get_tensor("model.norm.weight"),
-            lm_head: get_tensor("lm_head.weight"),
-        }
+        let embedding_table = get_weight(embedding_name(checkpoint, config), quant.embedding)?;
+        let lm_head = if !config.tie_word_embeddings {
+            get_weight(LM_HEAD, quant.lm_head)?
+        } else if quant.lm_head == quant.embedding {
+            // 共享同一份存储
+            embedding_table.clone()
+        } else {
+            // 两者的量化格式不同，只能各存一份
+            get_weight(embedding_name(checkpoint, config), quant.lm_head)?
+        };
+
+        Ok(LLamaParams {
+            embedding_table,
+            rms_att_w: get_layer_tensors("input_layernorm.weight")?,
+            wq: get_layer_weights("self_attn.q_proj.weight", quant.attention)?,
+            wk: get_layer_weights("self_attn.k_proj.weight", quant.attention)?,
+            wv: get_layer_weights("self_attn.v_proj.weight", quant.attention)?,
+            wo: get_layer_weights("self_attn.o_proj.weight", quant.attention)?,
+            rms_ffn_w: get_layer_tensors("post_attention_layernorm.weight")?,
+            w_up: get_layer_weights("mlp.up_proj.weight", quant.ffn)?,
+            w_gate: get_layer_weights("mlp.gate_proj.weight", quant.ffn)?,
+            w_down: get_layer_weights("mlp.down_proj.weight", quant.ffn)?,
+            rms_out_w: get_tensor("model.norm.weight")?,
+            lm_head,
+        })
     }
 }
+
+#[test]
+fn test_load_half_precision() {
+    let values = [1.5f32, -2.0, 0.099609375, 0.0];
+    let f16_bytes: Vec<u8> = values
+        .iter()
+        .flat_map(|&x| f16::from_f32(x).to_le_bytes())
+        .collect();
+    let bf16_bytes: Vec<u8> = values
+        .iter()
+        .flat_map(|&x| bf16::from_f32(x).to_le_bytes())
+        .collect();
+    let tensors = [
+        (
+            "a",
+            TensorView::new(Dtype::F16, vec![2, 2], &f16_bytes).unwrap(),
+        ),
+        (
+            "b",
+            TensorView::new(Dtype::BF16, vec![4], &bf16_bytes).unwrap(),
+        ),
+    ];
+    let bytes = safetensors::serialize(tensors, &None).unwrap();
+    let safetensor = safetensors::SafeTensors::deserialize(&bytes).unwrap();
+
+    let a = convert_tensor::<f32>("a", &safetensor.tensor("a").unwrap()).unwrap();
+    assert_eq!(a.shape(), &vec![2, 2]);
+    assert_eq!(a.data(), &values);
+    let b = convert_tensor::<f32>("b", &safetensor.tensor("b").unwrap()).unwrap();
+    assert_eq!(b.shape(), &vec![4]);
+    assert_eq!(b.data(), &values);
+    // 半精度权重原样保留
+    let a = convert_tensor::<f16>("a", &safetensor.tensor("a").unwrap()).unwrap();
+    assert!(a.data().iter().zip(values).all(|(x, y)| x.to_f32() == y));
+    let b = convert_tensor::<bf16>("b", &safetensor.tensor("b").unwrap()).unwrap();
+    assert!(b.data().iter().zip(values).all(|(x, y)| x.to_f32() == y));
+}
+
+#[test]
+fn test_validate_shapes() {
+    use std::path::PathBuf;
+    let project_dir = env!("CARGO_MANIFEST_DIR");
+    let model_dir = PathBuf::from(project_dir).join("models").join("story");
+    let checkpoint = Checkpoint::from_model_dir(&model_dir).unwrap();
+    let mut config = LlamaConfigJson::from_model_dir(&model_dir).unwrap();
+    assert!(validate_shapes(&checkpoint, &config).is_ok());
+
+    // intermediate_size 错误时，每一层的三个 MLP 矩阵都应被一次报告
+    config.intermediate_size += 1;
+    let Err(LoadError::ShapeMismatch(mismatches)) = validate_shapes(&checkpoint, &config) else {
+        panic!("expected a shape mismatch report");
+    };
+    assert_eq!(mismatches.len(), 3 * config.num_hidden_layers);
+    assert!(mismatches.iter().all(|m| m.actual.is_some()));
+    let down = &mismatches[2];
+    assert_eq!(down.name, "model.layers.0.mlp.down_proj.weight");
+    assert_eq!(
+        down.expected,
+        [config.hidden_size, config.intermediate_size]
+    );
+    assert_eq!(
+        down.actual,
+        Some(vec![config.hidden_size, config.intermediate_size - 1])
+    );
+
+    // 缺失的参数与形状不匹配的参数在同一份报告中
+    config.num_hidden_layers += 1;
+    let Err(LoadError::ShapeMismatch(mismatches)) = validate_shapes(&checkpoint, &config) else {
+        panic!("expected a shape mismatch report");
+    };
+    let missing = mismatches.iter().filter(|m| m.actual.is_none()).count();
+    assert_eq!(missing, 9);
+    assert_eq!(
+        mismatches.len() - missing,
+        3 * (config.num_hidden_layers - 1)
+    );
+    assert_eq!(
+        mismatches.last().unwrap().name,
+        "model.layers.2.mlp.down_proj.weight"
+    );
+}
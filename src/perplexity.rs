@@ -0,0 +1,100 @@
+// Perplexity of a model over a token sequence, scored with a sliding window as in the
+// Hugging Face perplexity guide. Each window holds at most `window` tokens and starts
+// `stride` tokens after the previous one. A window only scores the tokens that no earlier
+// window scored, so with stride < window every token but the first is scored once, with
+// at least window - stride tokens of context once the first window is done.
+use crate::dtype::WeightType;
+use crate::model::Llama;
+use crate::sampling::log_softmax;
+use crate::tensor::Tensor;
+
+#[derive(Debug)]
+pub struct Perplexity {
+    pub nll: f64,      // summed negative log-likelihood of the scored tokens, in nats
+    pub tokens: usize, // number of scored tokens
+}
+
+impl Perplexity {
+    pub fn ppl(&self) -> f64 {
+        (self.nll / self.tokens as f64).exp()
+    }
+
+    pub fn bits_per_token(&self) -> f64 {
+        self.nll / self.tokens as f64 / std::f64::consts::LN_2
+    }
+}
+
+// `window` can be at most the model's max_position_embeddings, and 0 < stride <= window.
+// When stride == window the first token of each window has no context and is not scored.
+pub fn evaluate<T: WeightType>(
+    llama: &Llama<T>,
+    tokens: &[u32],
+    window: usize,
+    stride: usize,
+) -> Perplexity {
+    assert!(
+        window <= llama.max_seq_len(),
+        "window exceeds the model's context"
+    );
+    assert!(
+        stride > 0 && stride <= window,
+        "stride must be in 1..=window"
+    );
+
+    let mut result = Perplexity { nll: 0., tokens: 0 };
+    let mut scored = 1; // tokens[..scored] 已计分或没有上文
+    let mut begin = 0;
+    while scored < tokens.len() {
+        let end = (begin + window).min(tokens.len());
+        let input = Tensor::<u32>::new(tokens[begin..end].to_vec(), &vec![end - begin]);
+        let logits = llama.forward_all(&input, &mut llama.new_cache());
+        let vocab = logits.shape()[1];
+
+        // logits 的第 i 行预测 tokens[begin + i + 1]
+        let first = scored.max(begin + 1);
+        for (t, &token) in tokens[..end].iter().enumerate().skip(first) {
+            let row = &logits.data()[(t - begin - 1) * vocab..(t - begin) * vocab];
+            result.nll -= log_softmax(row)[token as usize] as f64;
+            result.tokens += 1;
+        }
+        scored = end;
+        begin += stride;
+    }
+    result
+}
+
+#[test]
+fn test_perplexity() {
+    use std::path::PathBuf;
+    let project_dir = env!("CARGO_MANIFEST_DIR");
+    let model_dir = PathBuf::from(project_dir).join("models").join("story");
+    let model = Llama::<f32>::from_safetensors(model_dir).unwrap();
+    let tokens = (0..24).map(|i| 3 + i * 37 % 2000).collect::<Vec<u32>>();
+
+    // 逐个 token 计算：tokens[t] 由第一个包含它的窗口计分，上文从该窗口的起点开始
+    let reference = |window: usize, stride: usize| {
+        let mut nll = 0f64;
+        for t in 1..tokens.len() {
+            let begin = (t + 1).saturating_sub(window).div_ceil(stride) * stride;
+            if begin == t {
+                continue;
+            }
+            let input = Tensor::<u32>::new(tokens[begin..t].to_vec(), &vec![t - begin]);
+            let logits = model.forward(&input, &mut model.new_cache());
+            nll -= log_softmax(logits.data())[tokens[t] as usize] as f64;
+        }
+        nll
+    };
+
+    for (window, stride, scored) in [(32, 16, 23), (8, 4, 23), (8, 3, 23), (6, 6, 20)] {
+        let result = evaluate(&model, &tokens, window, stride);
+        assert_eq!(result.tokens, scored);
+        let expected = reference(window, stride);
+        assert!(
+            (result.nll - expected).abs() < 1e-3,
+            "window {window}, stride {stride}: {} != {expected}",
+            result.nll
+        );
+        assert!((result.bits_per_token() - result.ppl().log2()).abs() < 1e-9);
+    }
+}
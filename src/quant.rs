@@ -0,0 +1,406 @@
+// Blockwise weight quantization.
+// Every row of a (rows, cols) matrix is cut into blocks of `QuantBlock::SIZE` consecutive
+// weights, and each block stores its own scale (and for Q4_1 a minimum) next to the
+// low-bit integers, as in the ggml Q8_0 / Q4_0 / Q4_1 formats. The matmul kernels read
+// the blocks directly through `WeightMatrix`.
+use crate::simd;
+use crate::weight::WeightMatrix;
+use half::f16;
+use std::sync::Arc;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum QuantType {
+    // 8-bit integers with one f16 scale per 32 weights
+    Q8_0,
+    // 4-bit integers with one f16 scale per group of 32 weights
+    Q4_0,
+    // 4-bit integers with one f16 scale and one f16 minimum per group of 32 weights
+    Q4_1,
+}
+
+impl QuantType {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "q8_0" => Some(QuantType::Q8_0),
+            "q4_0" => Some(QuantType::Q4_0),
+            "q4_1" => Some(QuantType::Q4_1),
+            _ => None,
+        }
+    }
+
+    // Number of weights per block; a quantized row must hold a whole number of blocks
+    pub fn block_size(self) -> usize {
+        match self {
+            QuantType::Q8_0 => BlockQ8_0::SIZE,
+            QuantType::Q4_0 => BlockQ4_0::SIZE,
+            QuantType::Q4_1 => BlockQ4_1::SIZE,
+        }
+    }
+}
+
+// Quantization format of each class of tensors; `None` keeps a class in the model's
+// storage type. Small classes that are sensitive to rounding, such as embedding_table
+// and lm_head, can be kept at a higher precision than the attention and MLP weights.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct QuantConfig {
+    pub embedding: Option<QuantType>,
+    pub attention: Option<QuantType>, // wq, wk, wv, wo
+    pub ffn: Option<QuantType>,       // w_up, w_gate, w_down
+    pub lm_head: Option<QuantType>,
+}
+
+impl QuantConfig {
+    // Every projection matrix (attention, MLP and lm_head) in `quant`, embedding_table unchanged
+    pub fn uniform(quant: QuantType) -> Self {
+        QuantConfig {
+            embedding: None,
+            attention: Some(quant),
+            ffn: Some(quant),
+            lm_head: Some(quant),
+        }
+    }
+
+    // "q4_0" for `uniform`, or a comma separated list of class=format pairs such as
+    // "attention=q4_0,ffn=q4_1,lm_head=q8_0" where "none" keeps a class unquantized
+    pub fn parse(spec: &str) -> Option<Self> {
+        if let Some(quant) = QuantType::from_name(spec.trim()) {
+            return Some(Self::uniform(quant));
+        }
+        let mut config = QuantConfig::default();
+        for item in spec.split(',') {
+            let (class, format) = item.split_once('=')?;
+            let format = match format.trim() {
+                "none" => None,
+                name => Some(QuantType::from_name(name)?),
+            };
+            match class.trim() {
+                "embedding" => config.embedding = format,
+                "attention" => config.attention = format,
+                "ffn" => config.ffn = format,
+                "lm_head" => config.lm_head = format,
+                _ => return None,
+            }
+        }
+        Some(config)
+    }
+}
+
+pub trait QuantBlock: Copy + Send + Sync + 'static {
+    // Number of weights in one block
+    const SIZE: usize;
+
+    fn quantize(x: &[f32]) -> Self;
+
+    // dst = the SIZE weights of this block
+    fn dequantize(&self, dst: &mut [f32]);
+
+    // dot(x, weights of this block)
+    fn dot(&self, x: &[f32]) -> f32;
+}
+
+// x ≈ d * qs
+#[derive(Clone, Copy, Debug)]
+pub struct BlockQ8_0 {
+    pub d: f16,
+    pub qs: [i8; 32],
+}
+
+impl QuantBlock for BlockQ8_0 {
+    const SIZE: usize = 32;
+
+    fn quantize(x: &[f32]) -> Self {
+        assert_eq!(x.len(), Self::SIZE);
+        let amax = x.iter().fold(0f32, |m, v| m.max(v.abs()));
+        let d = amax / 127.;
+        let inv_d = if d == 0. { 0. } else { 1. / d };
+        let mut qs = [0; 32];
+        for (q, &v) in qs.iter_mut().zip(x) {
+            *q = (v * inv_d).round() as i8;
+        }
+        BlockQ8_0 {
+            d: f16::from_f32(d),
+            qs,
+        }
+    }
+
+    fn dequantize(&self, dst: &mut [f32]) {
+        let d = self.d.to_f32();
+        for (y, &q) in dst.iter_mut().zip(&self.qs) {
+            *y = q as f32 * d;
+        }
+    }
+
+    fn dot(&self, x: &[f32]) -> f32 {
+        let mut q = [0.; 32];
+        for (y, &v) in q.iter_mut().zip(&self.qs) {
+            *y = v as f32;
+        }
+        simd::dot(x, &q) * self.d.to_f32()
+    }
+}
+
+// x ≈ d * (q - 8); weight j of the block is in the low nibble of qs[j % 16] for j < 16
+// and in the high nibble for j >= 16
+#[derive(Clone, Copy, Debug)]
+pub struct BlockQ4_0 {
+    pub d: f16,
+    pub qs: [u8; 16],
+}
+
+impl QuantBlock for BlockQ4_0 {
+    const SIZE: usize = 32;
+
+    fn quantize(x: &[f32]) -> Self {
+        assert_eq!(x.len(), Self::SIZE);
+        // the value with the largest magnitude maps to -8, so its sign picks the scale's sign
+        let max = x
+            .iter()
+            .fold(0f32, |m, &v| if v.abs() > m.abs() { v } else { m });
+        let d = max / -8.;
+        let inv_d = if d == 0. { 0. } else { 1. / d };
+        let q = |v: f32| ((v * inv_d + 8.5) as i32).clamp(0, 15) as u8;
+        let mut qs = [0; 16];
+        for (j, b) in qs.iter_mut().enumerate() {
+            *b = q(x[j]) | (q(x[j + 16]) << 4);
+        }
+        BlockQ4_0 {
+            d: f16::from_f32(d),
+            qs,
+        }
+    }
+
+    fn dequantize(&self, dst: &mut [f32]) {
+        let d = self.d.to_f32();
+        for (j, &b) in self.qs.iter().enumerate() {
+            dst[j] = ((b & 0x0f) as i32 - 8) as f32 * d;
+            dst[j + 16] = ((b >> 4) as i32 - 8) as f32 * d;
+        }
+    }
+
+    fn dot(&self, x: &[f32]) -> f32 {
+        let mut q = [0.; 32];
+        for (j, &b) in self.qs.iter().enumerate() {
+            q[j] = ((b & 0x0f) as i32 - 8) as f32;
+            q[j + 16] = ((b >> 4) as i32 - 8) as f32;
+        }
+        simd::dot(x, &q) * self.d.to_f32()
+    }
+}
+
+// x ≈ d * q + m, with the nibble layout of BlockQ4_0
+#[derive(Clone, Copy, Debug)]
+pub struct BlockQ4_1 {
+    pub d: f16,
+    pub m: f16,
+    pub qs: [u8; 16],
+}
+
+impl QuantBlock for BlockQ4_1 {
+    const SIZE: usize = 32;
+
+    fn quantize(x: &[f32]) -> Self {
+        assert_eq!(x.len(), Self::SIZE);
+        let min = x.iter().copied().fold(f32::INFINITY, f32::min);
+        let max = x.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let d = (max - min) / 15.;
+        let inv_d = if d == 0. { 0. } else { 1. / d };
+        let q = |v: f32| (((v - min) * inv_d + 0.5) as i32).clamp(0, 15) as u8;
+        let mut qs = [0; 16];
+        for (j, b) in qs.iter_mut().enumerate() {
+            *b = q(x[j]) | (q(x[j + 16]) << 4);
+        }
+        BlockQ4_1 {
+            d: f16::from_f32(d),
+            m: f16::from_f32(min),
+            qs,
+        }
+    }
+
+    fn dequantize(&self, dst: &mut [f32]) {
+        let (d, m) = (self.d.to_f32(), self.m.to_f32());
+        for (j, &b) in self.qs.iter().enumerate() {
+            dst[j] = (b & 0x0f) as f32 * d + m;
+            dst[j + 16] = (b >> 4) as f32 * d + m;
+        }
+    }
+
+    fn dot(&self, x: &[f32]) -> f32 {
+        let mut q = [0.; 32];
+        for (j, &b) in self.qs.iter().enumerate() {
+            q[j] = (b & 0x0f) as f32;
+            q[j + 16] = (b >> 4) as f32;
+        }
+        // sum(x * (d * q + m)) = d * dot(x, q) + m * sum(x)
+        simd::dot(x, &q) * self.d.to_f32() + x.iter().sum::<f32>() * self.m.to_f32()
+    }
+}
+
+// A (rows, cols) matrix stored as rows * cols / B::SIZE blocks; clones share the blocks
+#[derive(Clone)]
+pub struct QTensor<B> {
+    blocks: Arc<[B]>,
+    rows: usize,
+    cols: usize,
+}
+
+impl<B: QuantBlock> QTensor<B> {
+    // Quantize a row-major (rows, cols) matrix; cols must be a multiple of the block size
+    pub fn quantize(data: &[f32], rows: usize, cols: usize) -> Self {
+        assert_eq!(data.len(), rows * cols);
+        assert!(
+            cols.is_multiple_of(B::SIZE),
+            "cols ({cols}) must be a multiple of the quantization block size ({})",
+            B::SIZE
+        );
+        QTensor {
+            blocks: data.chunks_exact(B::SIZE).map(B::quantize).collect(),
+            rows,
+            cols,
+        }
+    }
+
+    // Bytes used by the quantized weights
+    #[allow(unused)]
+    pub fn size_in_bytes(&self) -> usize {
+        self.blocks.len() * std::mem::size_of::<B>()
+    }
+
+    fn row_blocks(&self, row: usize) -> &[B] {
+        let n = self.cols / B::SIZE;
+        &self.blocks[row * n..][..n]
+    }
+}
+
+impl<B: QuantBlock> WeightMatrix for QTensor<B> {
+    fn rows(&self) -> usize {
+        self.rows
+    }
+
+    fn cols(&self) -> usize {
+        self.cols
+    }
+
+    fn row_to_f32(&self, row: usize, col0: usize, dst: &mut [f32]) {
+        assert!(col0.is_multiple_of(B::SIZE) && dst.len().is_multiple_of(B::SIZE));
+        let blocks = &self.row_blocks(row)[col0 / B::SIZE..];
+        for (block, dst) in blocks.iter().zip(dst.chunks_exact_mut(B::SIZE)) {
+            block.dequantize(dst);
+        }
+    }
+
+    fn row_dot(&self, row: usize, x: &[f32]) -> f32 {
+        self.row_blocks(row)
+            .iter()
+            .zip(x.chunks_exact(B::SIZE))
+            .map(|(block, x)| block.dot(x))
+            .sum()
+    }
+}
+
+#[cfg(test)]
+fn test_matrix(rows: usize, cols: usize) -> Vec<f32> {
+    (0..rows * cols)
+        .map(|i| ((i * 37 % 101) as f32 - 50.) * 0.013)
+        .collect()
+}
+
+#[test]
+fn test_q8_0_roundtrip() {
+    let (rows, cols) = (3, 64);
+    let data = test_matrix(rows, cols);
+    let q = QTensor::<BlockQ8_0>::quantize(&data, rows, cols);
+    assert_eq!(q.size_in_bytes(), rows * cols / 32 * 34);
+    let mut row = vec![0.; cols];
+    for r in 0..rows {
+        q.row_to_f32(r, 0, &mut row);
+        let amax = data[r * cols..][..cols]
+            .iter()
+            .fold(0f32, |m, v| m.max(v.abs()));
+        for (x, y) in row.iter().zip(&data[r * cols..][..cols]) {
+            // 每个值的误差不超过半个量化步长（外加 f16 scale 的舍入）
+            assert!((x - y).abs() <= amax / 127. * 0.51);
+        }
+    }
+}
+
+#[cfg(test)]
+fn check_q4_roundtrip<B: QuantBlock>() {
+    let (rows, cols) = (3, 64);
+    let data = test_matrix(rows, cols);
+    let q = QTensor::<B>::quantize(&data, rows, cols);
+    let mut row = vec![0.; cols];
+    for r in 0..rows {
+        q.row_to_f32(r, 0, &mut row);
+        for (x, y) in row.chunks(32).zip(data[r * cols..][..cols].chunks(32)) {
+            let min = y.iter().copied().fold(f32::INFINITY, f32::min);
+            let max = y.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+            let amax = max.abs().max(min.abs());
+            // 误差不超过一个量化步长
+            let step = (max - min).max(2. * amax) / 15.;
+            for (a, b) in x.iter().zip(y) {
+                assert!((a - b).abs() <= step, "row {r}: {a} vs {b}");
+            }
+        }
+    }
+}
+
+#[test]
+fn test_q4_roundtrip() {
+    check_q4_roundtrip::<BlockQ4_0>();
+    check_q4_roundtrip::<BlockQ4_1>();
+    let q = QTensor::<BlockQ4_0>::quantize(&test_matrix(2, 64), 2, 64);
+    assert_eq!(q.size_in_bytes(), 2 * 64 / 32 * 18);
+}
+
+#[test]
+fn test_quant_config_parse() {
+    assert_eq!(
+        QuantConfig::parse("q4_0"),
+        Some(QuantConfig::uniform(QuantType::Q4_0))
+    );
+    assert_eq!(
+        QuantConfig::parse("attention=q4_0, ffn=q4_1,lm_head=q8_0,embedding=none"),
+        Some(QuantConfig {
+            embedding: None,
+            attention: Some(QuantType::Q4_0),
+            ffn: Some(QuantType::Q4_1),
+            lm_head: Some(QuantType::Q8_0),
+        })
+    );
+    assert_eq!(QuantConfig::parse("ffn=q3"), None);
+    assert_eq!(QuantConfig::parse("mlp=q4_0"), None);
+}
+
+#[cfg(test)]
+fn check_matmul<B: QuantBlock>() {
+    use crate::operators::matmul_transb;
+    use crate::tensor::Tensor;
+    let (m, n, k) = (3, 40, 96);
+    let a = Tensor::<f32>::new(test_matrix(m, k), &vec![m, k]);
+    let b_data = test_matrix(n, k);
+    let q = QTensor::<B>::quantize(&b_data, n, k);
+
+    // 量化矩阵与其反量化得到的稠密矩阵应给出相同的结果
+    let mut dequantized = vec![0.; n * k];
+    for (r, row) in dequantized.chunks_exact_mut(k).enumerate() {
+        q.row_to_f32(r, 0, row);
+    }
+    let dense = Tensor::<f32>::new(dequantized, &vec![n, k]);
+    for rows in [1, m] {
+        let a = a.slice(0, &vec![rows, k]);
+        let mut expected = Tensor::<f32>::default(&vec![rows, n]);
+        matmul_transb(&mut expected, 0., &a, &dense, 1.);
+        let mut c = Tensor::<f32>::default(&vec![rows, n]);
+        matmul_transb(&mut c, 0., &a, &q, 1.);
+        for (x, y) in c.data().iter().zip(expected.data()) {
+            assert!((x - y).abs() < 1e-4);
+        }
+    }
+}
+
+#[test]
+fn test_quantized_matmul() {
+    check_matmul::<BlockQ8_0>();
+    check_matmul::<BlockQ4_0>();
+    check_matmul::<BlockQ4_1>();
+}
@@ -0,0 +1,527 @@
+// Turning logits into the next token.
+// A `SamplingPipeline` runs a chain of `LogitsProcessor`s over the (vocab,) logits and hands
+// the result to a `Sampler`. Processors rewrite logits in place; filters such as top-k drop
+// a token by setting its logit to -inf, so later processors and the sampler never pick it.
+use crate::generation::{GenerationConfig, MirostatConfig};
+use crate::tensor::Tensor;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::fmt;
+use std::sync::Arc;
+
+pub trait LogitsProcessor: Send + Sync {
+    // Rewrite `logits` given `tokens`, the tokens generated after the prompt so far
+    fn process(&self, logits: &mut Tensor<f32>, tokens: &[u32]);
+}
+
+// Picks the next token from the processed logits
+pub trait Sampler: Send {
+    fn sample(&mut self, logits: &Tensor<f32>) -> u32;
+}
+
+// Extra processors set on a `GenerationConfig`, run after the built-in ones.
+// Two lists are equal when they hold the same processor instances.
+#[derive(Clone, Default)]
+pub struct LogitsProcessors(pub Vec<Arc<dyn LogitsProcessor>>);
+
+impl fmt::Debug for LogitsProcessors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "LogitsProcessors({} processors)", self.0.len())
+    }
+}
+
+impl PartialEq for LogitsProcessors {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.len() == other.0.len() && self.0.iter().zip(&other.0).all(|(a, b)| Arc::ptr_eq(a, b))
+    }
+}
+
+pub struct SamplingPipeline {
+    processors: Vec<Arc<dyn LogitsProcessor>>,
+    sampler: Box<dyn Sampler>,
+}
+
+impl SamplingPipeline {
+    pub fn new(processors: Vec<Arc<dyn LogitsProcessor>>, sampler: Box<dyn Sampler>) -> Self {
+        SamplingPipeline {
+            processors,
+            sampler,
+        }
+    }
+
+    // Penalties and biases, then temperature, top-k, top-p, min-p and typical-p, then the
+    // processors added to the config, in the order Hugging Face applies them. do_sample
+    // unset or false, a zero temperature, top_k = 1 or top_p = 0 decode greedily. Mirostat does its
+    // own truncation, so it replaces top-k, top-p, min-p and typical-p.
+    pub fn from_config(config: &GenerationConfig) -> Self {
+        let mut processors = penalties(config);
+        let mirostat = config.mirostat;
+        let greedy = config.do_sample != Some(true)
+            || config.temperature <= 0.
+            || (mirostat.is_none() && (config.top_k == 1 || config.top_p <= 0.));
+        if !greedy && config.temperature != 1. {
+            processors.push(Arc::new(Temperature(config.temperature)));
+        }
+        if !greedy && mirostat.is_none() {
+            if config.top_k > 1 {
+                processors.push(Arc::new(TopK(config.top_k as usize)));
+            }
+            if config.top_p < 1. {
+                processors.push(Arc::new(TopP(config.top_p)));
+            }
+            if config.min_p > 0. {
+                processors.push(Arc::new(MinP(config.min_p)));
+            }
+            if config.typical_p < 1. {
+                processors.push(Arc::new(TypicalP(config.typical_p)));
+            }
+        }
+        processors.extend(config.logits_processors.0.iter().cloned());
+
+        let sampler: Box<dyn Sampler> = match mirostat {
+            _ if greedy => Box::new(Greedy),
+            Some(MirostatConfig { tau, eta }) => Box::new(Mirostat::new(tau, eta, config.seed)),
+            None => Box::new(Multinomial::new(config.seed)),
+        };
+        Self::new(processors, sampler)
+    }
+
+    // Only the penalties, biases and custom processors, for scoring rather than sampling
+    // (beam search); `sample` picks the most likely token
+    pub fn scoring(config: &GenerationConfig) -> Self {
+        let mut processors = penalties(config);
+        processors.extend(config.logits_processors.0.iter().cloned());
+        Self::new(processors, Box::new(Greedy))
+    }
+
+    pub fn process(&self, mut logits: Tensor<f32>, tokens: &[u32]) -> Tensor<f32> {
+        for processor in &self.processors {
+            processor.process(&mut logits, tokens);
+        }
+        logits
+    }
+
+    pub fn sample(&mut self, logits: Tensor<f32>, tokens: &[u32]) -> u32 {
+        let logits = self.process(logits, tokens);
+        self.sampler.sample(&logits)
+    }
+}
+
+// Penalties and logit biases: the processors that reshape the distribution without
+// truncating it
+fn penalties(config: &GenerationConfig) -> Vec<Arc<dyn LogitsProcessor>> {
+    let mut processors: Vec<Arc<dyn LogitsProcessor>> = vec![];
+    let window = config.penalty_window;
+    if config.repetition_penalty != 1. {
+        processors.push(Arc::new(RepetitionPenalty {
+            penalty: config.repetition_penalty,
+            window,
+        }));
+    }
+    if config.frequency_penalty != 0. || config.presence_penalty != 0. {
+        processors.push(Arc::new(FrequencyPresencePenalty {
+            frequency: config.frequency_penalty,
+            presence: config.presence_penalty,
+            window,
+        }));
+    }
+    if !config.logit_bias.is_empty() {
+        processors.push(Arc::new(LogitBias(config.logit_bias.clone())));
+    }
+    processors
+}
+
+// logits /= temperature
+pub struct Temperature(pub f32);
+
+impl LogitsProcessor for Temperature {
+    fn process(&self, logits: &mut Tensor<f32>, _tokens: &[u32]) {
+        let inv_t = 1. / self.0;
+        unsafe { logits.data_mut() }
+            .iter_mut()
+            .for_each(|x| *x *= inv_t);
+    }
+}
+
+// Keep the k most likely tokens
+pub struct TopK(pub usize);
+
+impl LogitsProcessor for TopK {
+    fn process(&self, logits: &mut Tensor<f32>, _tokens: &[u32]) {
+        let data = unsafe { logits.data_mut() };
+        if self.0 == 0 || self.0 >= data.len() {
+            return;
+        }
+        let mut sorted = data.to_vec();
+        let (_, &mut kth, _) = sorted.select_nth_unstable_by(self.0 - 1, |a, b| b.total_cmp(a));
+        // Ties with the k-th logit are kept
+        data.iter_mut()
+            .filter(|x| **x < kth)
+            .for_each(|x| *x = f32::NEG_INFINITY);
+    }
+}
+
+// Keep the smallest set of most likely tokens whose probabilities add up to at least p
+pub struct TopP(pub f32);
+
+impl LogitsProcessor for TopP {
+    fn process(&self, logits: &mut Tensor<f32>, _tokens: &[u32]) {
+        let probs = softmax(logits.data());
+        let mut order = (0..probs.len()).collect::<Vec<_>>();
+        order.sort_unstable_by(|&a, &b| probs[b].total_cmp(&probs[a]));
+        let data = unsafe { logits.data_mut() };
+        let mut cumulative = 0.;
+        for (rank, &i) in order.iter().enumerate() {
+            if rank > 0 && cumulative >= self.0 {
+                data[i] = f32::NEG_INFINITY;
+            }
+            cumulative += probs[i];
+        }
+    }
+}
+
+// Keep the tokens whose probability is at least p times that of the most likely token
+pub struct MinP(pub f32);
+
+impl LogitsProcessor for MinP {
+    fn process(&self, logits: &mut Tensor<f32>, _tokens: &[u32]) {
+        // p_i >= p * p_max  <=>  logit_i >= logit_max + ln(p)
+        let data = unsafe { logits.data_mut() };
+        let max = data.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let threshold = max + self.0.ln();
+        data.iter_mut()
+            .filter(|x| **x < threshold)
+            .for_each(|x| *x = f32::NEG_INFINITY);
+    }
+}
+
+// Locally typical sampling (Meister et al., 2022): rank tokens by how close their
+// information content -ln(p_i) is to the entropy of the distribution, and keep the
+// smallest set of the most typical tokens whose probabilities add up to at least p
+pub struct TypicalP(pub f32);
+
+impl LogitsProcessor for TypicalP {
+    fn process(&self, logits: &mut Tensor<f32>, _tokens: &[u32]) {
+        let probs = softmax(logits.data());
+        let entropy = -probs
+            .iter()
+            .filter(|&&p| p > 0.)
+            .map(|&p| p * p.ln())
+            .sum::<f32>();
+        let score = |i: usize| (-probs[i].ln() - entropy).abs();
+        let mut order = (0..probs.len()).collect::<Vec<_>>();
+        order.sort_unstable_by(|&a, &b| score(a).total_cmp(&score(b)));
+        let data = unsafe { logits.data_mut() };
+        let mut cumulative = 0.;
+        for (rank, &i) in order.iter().enumerate() {
+            if rank > 0 && cumulative >= self.0 {
+                data[i] = f32::NEG_INFINITY;
+            }
+            cumulative += probs[i];
+        }
+    }
+}
+
+// The last `window` tokens, or all of them when window is None
+fn look_back(tokens: &[u32], window: Option<usize>) -> &[u32] {
+    match window {
+        Some(window) => &tokens[tokens.len().saturating_sub(window)..],
+        None => tokens,
+    }
+}
+
+// Repetition penalty as in CTRL / Hugging Face: the logit of every token that appears in
+// the look-back window is divided by the penalty when positive and multiplied by it when
+// negative
+pub struct RepetitionPenalty {
+    pub penalty: f32,
+    pub window: Option<usize>,
+}
+
+impl LogitsProcessor for RepetitionPenalty {
+    fn process(&self, logits: &mut Tensor<f32>, tokens: &[u32]) {
+        let data = unsafe { logits.data_mut() };
+        let mut seen = vec![false; data.len()];
+        for &token in look_back(tokens, self.window) {
+            let token = token as usize;
+            if !std::mem::replace(&mut seen[token], true) {
+                let x = &mut data[token];
+                *x = if *x > 0. {
+                    *x / self.penalty
+                } else {
+                    *x * self.penalty
+                };
+            }
+        }
+    }
+}
+
+// Frequency and presence penalties as in the OpenAI API:
+// logit -= frequency * count + presence * (count > 0), counting the look-back window
+pub struct FrequencyPresencePenalty {
+    pub frequency: f32,
+    pub presence: f32,
+    pub window: Option<usize>,
+}
+
+impl LogitsProcessor for FrequencyPresencePenalty {
+    fn process(&self, logits: &mut Tensor<f32>, tokens: &[u32]) {
+        let data = unsafe { logits.data_mut() };
+        let mut counts = vec![0u32; data.len()];
+        for &token in look_back(tokens, self.window) {
+            counts[token as usize] += 1;
+        }
+        for (x, &count) in data.iter_mut().zip(&counts) {
+            if count > 0 {
+                *x -= self.frequency * count as f32 + self.presence;
+            }
+        }
+    }
+}
+
+// logits[token] += bias for every (token, bias); -inf bans a token. Tokens outside the
+// vocabulary are ignored
+pub struct LogitBias(pub Vec<(u32, f32)>);
+
+impl LogitsProcessor for LogitBias {
+    fn process(&self, logits: &mut Tensor<f32>, _tokens: &[u32]) {
+        let data = unsafe { logits.data_mut() };
+        for &(token, bias) in &self.0 {
+            if let Some(x) = data.get_mut(token as usize) {
+                *x += bias;
+            }
+        }
+    }
+}
+
+// The most likely token
+pub struct Greedy;
+
+impl Sampler for Greedy {
+    fn sample(&mut self, logits: &Tensor<f32>) -> u32 {
+        argmax(logits.data())
+    }
+}
+
+// A token drawn from softmax(logits)
+pub struct Multinomial {
+    rng: StdRng,
+}
+
+impl Multinomial {
+    // The same seed always draws the same tokens; None seeds from the OS
+    pub fn new(seed: Option<u64>) -> Self {
+        let rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        Multinomial { rng }
+    }
+}
+
+impl Sampler for Multinomial {
+    fn sample(&mut self, logits: &Tensor<f32>) -> u32 {
+        let probs = softmax(logits.data());
+        let mut r = self.rng.gen::<f32>();
+        for (i, &p) in probs.iter().enumerate() {
+            if r < p {
+                return i as u32;
+            }
+            r -= p;
+        }
+        // Rounding left r just above the total; fall back to the most likely token
+        argmax(logits.data())
+    }
+}
+
+// Mirostat v2 (Basu et al., 2021): only tokens whose surprise -log2(p) is at most mu are
+// kept, and after every draw mu moves by eta towards holding the observed surprise at the
+// target tau. mu lives in the sampler, so it carries over between the steps of one
+// generate / stream_generate call.
+pub struct Mirostat {
+    tau: f32,
+    eta: f32,
+    mu: f32,
+    rng: StdRng,
+}
+
+impl Mirostat {
+    pub fn new(tau: f32, eta: f32, seed: Option<u64>) -> Self {
+        let rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        Mirostat {
+            tau,
+            eta,
+            mu: 2. * tau,
+            rng,
+        }
+    }
+}
+
+impl Sampler for Mirostat {
+    fn sample(&mut self, logits: &Tensor<f32>) -> u32 {
+        let probs = softmax(logits.data());
+        let mut order = (0..probs.len()).collect::<Vec<_>>();
+        order.sort_unstable_by(|&a, &b| probs[b].total_cmp(&probs[a]));
+        // The most likely token is always kept
+        let kept = 1 + order[1..]
+            .iter()
+            .take_while(|&&i| -probs[i].log2() <= self.mu)
+            .count();
+        let order = &order[..kept];
+
+        let total = order.iter().map(|&i| probs[i]).sum::<f32>();
+        let mut r = self.rng.gen::<f32>() * total;
+        let token = *order
+            .iter()
+            .find(|&&i| {
+                r -= probs[i];
+                r < 0.
+            })
+            .unwrap_or(&order[0]);
+
+        let surprise = -(probs[token] / total).log2();
+        self.mu -= self.eta * (surprise - self.tau);
+        token as u32
+    }
+}
+
+fn argmax(x: &[f32]) -> u32 {
+    (0..x.len()).max_by(|&a, &b| x[a].total_cmp(&x[b])).unwrap() as u32
+}
+
+// exp(x - max) / sum(exp(x - max)); tokens at -inf get probability 0
+fn softmax(x: &[f32]) -> Vec<f32> {
+    let max = x.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let mut probs = x.iter().map(|&v| (v - max).exp()).collect::<Vec<_>>();
+    let sum = probs.iter().sum::<f32>();
+    probs.iter_mut().for_each(|p| *p /= sum);
+    probs
+}
+
+// x - max - ln(sum(exp(x - max))); tokens at -inf stay at -inf
+pub fn log_softmax(x: &[f32]) -> Vec<f32> {
+    let max = x.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let log_sum = x.iter().map(|&v| (v - max).exp()).sum::<f32>().ln() + max;
+    x.iter().map(|&v| v - log_sum).collect()
+}
+
+// The n largest values of x with their indices, largest first
+pub fn top_n(x: &[f32], n: usize) -> Vec<(u32, f32)> {
+    let mut indices = (0..x.len()).collect::<Vec<_>>();
+    if n < x.len() {
+        indices.select_nth_unstable_by(n, |&a, &b| x[b].total_cmp(&x[a]));
+        indices.truncate(n);
+    }
+    indices.sort_unstable_by(|&a, &b| x[b].total_cmp(&x[a]));
+    indices.into_iter().map(|i| (i as u32, x[i])).collect()
+}
+
+#[test]
+fn test_logits_processors() {
+    let logits = || Tensor::<f32>::new(vec![2., -2., 3., 0.5, 1.], &vec![5]);
+    let process = |processor: &dyn LogitsProcessor, tokens: &[u32]| {
+        let mut x = logits();
+        processor.process(&mut x, tokens);
+        x.data().to_vec()
+    };
+    let inf = f32::NEG_INFINITY;
+
+    assert_eq!(process(&Temperature(0.5), &[]), [4., -4., 6., 1., 2.]);
+    assert_eq!(process(&TopK(2), &[]), [2., inf, 3., inf, inf]);
+    assert_eq!(process(&TopK(5), &[]), logits().data());
+    // softmax ≈ [0.23, 0.004, 0.63, 0.05, 0.09]
+    assert_eq!(process(&TopP(0.8), &[]), [2., inf, 3., inf, inf]);
+    assert_eq!(process(&TopP(0.5), &[]), [inf, inf, 3., inf, inf]);
+    // p_max * 0.1 ≈ 0.063
+    assert_eq!(process(&MinP(0.1), &[]), [2., inf, 3., inf, 1.]);
+    // 熵 ≈ 1.00，-ln(p) ≈ [1.46, 5.46, 0.46, 2.96, 2.46]，最典型的依次是 0、2、4
+    assert_eq!(process(&TypicalP(0.8), &[]), [2., inf, 3., inf, inf]);
+    assert_eq!(process(&TypicalP(0.9), &[]), [2., inf, 3., inf, 1.]);
+    // 重复出现的 token 只惩罚一次
+    let repetition = |window| RepetitionPenalty {
+        penalty: 2.,
+        window,
+    };
+    assert_eq!(
+        process(&repetition(None), &[0, 1, 1, 3, 0]),
+        [1., -4., 3., 0.25, 1.]
+    );
+    assert_eq!(
+        process(&repetition(Some(2)), &[0, 1, 1, 3, 0]),
+        [1., -2., 3., 0.25, 1.]
+    );
+    let frequency_presence = |window| FrequencyPresencePenalty {
+        frequency: 0.5,
+        presence: 0.25,
+        window,
+    };
+    assert_eq!(
+        process(&frequency_presence(None), &[0, 1, 1, 3, 1]),
+        [1.25, -3.75, 3., -0.25, 1.]
+    );
+    assert_eq!(
+        process(&frequency_presence(Some(3)), &[0, 1, 1, 3, 1]),
+        [2., -3.25, 3., -0.25, 1.]
+    );
+    assert_eq!(
+        process(&LogitBias(vec![(2, inf), (1, 5.)]), &[]),
+        [2., 3., inf, 0.5, 1.]
+    );
+    // 超出词表的 token 被忽略
+    assert_eq!(
+        process(&LogitBias(vec![(5, 1.), (u32::MAX, -inf), (0, 1.)]), &[]),
+        [3., -2., 3., 0.5, 1.]
+    );
+}
+
+#[test]
+fn test_mirostat() {
+    let logits = Tensor::<f32>::new(vec![2., -2., 3., 0.5, 1.], &vec![5]);
+    // surprise = -log2(p) ≈ [2.11, 7.88, 0.67, 4.27, 3.56]
+    let mut mirostat = Mirostat::new(0.25, 0.5, Some(1));
+    // mu = 0.5 只保留最可能的 token，其归一化后的 surprise 为 0
+    assert_eq!(mirostat.sample(&logits), 2);
+    assert_eq!(mirostat.mu, 0.5 + 0.5 * 0.25);
+    // 目标 surprise 很高时 mu 增大，截断逐渐放宽
+    let mut mirostat = Mirostat::new(10., 0.5, Some(1));
+    let tokens = (0..50)
+        .map(|_| mirostat.sample(&logits))
+        .collect::<Vec<_>>();
+    assert!(mirostat.mu > 20.);
+    assert!(tokens.iter().any(|&t| t != 2));
+
+    // 在默认配置上设置 mirostat 即开始采样，而不是贪心解码
+    let config = GenerationConfig::default().mirostat(10., 0.5).seed(1);
+    let mut pipeline = SamplingPipeline::from_config(&config);
+    let fresh = || Tensor::<f32>::new(vec![2., -2., 3., 0.5, 1.], &vec![5]);
+    assert!((0..50).any(|_| pipeline.sample(fresh(), &[]) != 2));
+}
+
+#[test]
+fn test_sampling_pipeline() {
+    let logits = || Tensor::<f32>::new(vec![2., -2., 3., 0.5, 1.], &vec![5]);
+    let greedy = GenerationConfig::default().temperature(0.);
+    assert_eq!(
+        SamplingPipeline::from_config(&greedy).sample(logits(), &[]),
+        2
+    );
+    // 只剩一个候选时，随机采样也是确定的
+    let top1 = GenerationConfig::default().do_sample(true).top_p(0.5);
+    let mut pipeline = SamplingPipeline::from_config(&top1);
+    assert!((0..20).all(|_| pipeline.sample(logits(), &[]) == 2));
+    // 惩罚只统计窗口内已生成的 tokens
+    let penalized = greedy.clone().presence_penalty(2.).penalty_window(1);
+    let mut pipeline = SamplingPipeline::from_config(&penalized);
+    assert_eq!(pipeline.sample(logits(), &[2]), 0);
+    assert_eq!(pipeline.sample(logits(), &[2, 4]), 2);
+    // 自定义 processor 在内置 processor 之后执行
+    let banned = GenerationConfig::default()
+        .do_sample(true)
+        .top_k(2)
+        .logits_processor(Arc::new(LogitBias(vec![(2, f32::NEG_INFINITY)])));
+    let mut pipeline = SamplingPipeline::from_config(&banned);
+    assert!((0..20).all(|_| pipeline.sample(logits(), &[]) == 0));
+}
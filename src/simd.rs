@@ -0,0 +1,567 @@
+// Vectorized f32 kernels used by the operators.
+// The instruction set is detected once at runtime: AVX2+FMA on x86_64, NEON on aarch64,
+// and a portable scalar path everywhere else. The scalar path is also the reference the
+// SIMD paths are tested against.
+use std::sync::atomic::{AtomicU8, Ordering};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum Isa {
+    Scalar = 1,
+    #[allow(unused)]
+    Avx2Fma = 2,
+    #[allow(unused)]
+    Neon = 3,
+}
+
+// 0 until the first kernel call runs detection
+static DETECTED_ISA: AtomicU8 = AtomicU8::new(0);
+
+// The best instruction set supported by the running CPU
+#[inline]
+pub fn isa() -> Isa {
+    match DETECTED_ISA.load(Ordering::Relaxed) {
+        1 => Isa::Scalar,
+        2 => Isa::Avx2Fma,
+        3 => Isa::Neon,
+        _ => {
+            let isa = detect();
+            DETECTED_ISA.store(isa as u8, Ordering::Relaxed);
+            isa
+        }
+    }
+}
+
+fn detect() -> Isa {
+    #[cfg(target_arch = "x86_64")]
+    if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
+        return Isa::Avx2Fma;
+    }
+    #[cfg(target_arch = "aarch64")]
+    if std::arch::is_aarch64_feature_detected!("neon") {
+        return Isa::Neon;
+    }
+    Isa::Scalar
+}
+
+// sum(a[i] * b[i])
+#[inline]
+pub fn dot(a: &[f32], b: &[f32]) -> f32 {
+    assert_eq!(a.len(), b.len());
+    match isa() {
+        #[cfg(target_arch = "x86_64")]
+        Isa::Avx2Fma => unsafe { avx2::dot(a, b) },
+        #[cfg(target_arch = "aarch64")]
+        Isa::Neon => unsafe { neon::dot(a, b) },
+        _ => scalar::dot(a, b),
+    }
+}
+
+// [dot(x, w[0]), dot(x, w[1]), dot(x, w[2]), dot(x, w[3])], reading x once for all four rows
+#[inline]
+pub fn dot4(x: &[f32], w: [&[f32]; 4]) -> [f32; 4] {
+    assert!(w.iter().all(|w| w.len() == x.len()));
+    match isa() {
+        #[cfg(target_arch = "x86_64")]
+        Isa::Avx2Fma => unsafe { avx2::dot4(x, w) },
+        #[cfg(target_arch = "aarch64")]
+        Isa::Neon => unsafe { neon::dot4(x, w) },
+        _ => scalar::dot4(x, w),
+    }
+}
+
+// y[i] += alpha * x[i]
+#[inline]
+pub fn axpy(y: &mut [f32], alpha: f32, x: &[f32]) {
+    assert_eq!(y.len(), x.len());
+    match isa() {
+        #[cfg(target_arch = "x86_64")]
+        Isa::Avx2Fma => unsafe { avx2::axpy(y, alpha, x) },
+        #[cfg(target_arch = "aarch64")]
+        Isa::Neon => unsafe { neon::axpy(y, alpha, x) },
+        _ => scalar::axpy(y, alpha, x),
+    }
+}
+
+// y[i] = scale * w[i] * x[i]
+#[inline]
+pub fn scale_mul(y: &mut [f32], scale: f32, w: &[f32], x: &[f32]) {
+    assert!(y.len() == w.len() && y.len() == x.len());
+    match isa() {
+        #[cfg(target_arch = "x86_64")]
+        Isa::Avx2Fma => unsafe { avx2::scale_mul(y, scale, w, x) },
+        #[cfg(target_arch = "aarch64")]
+        Isa::Neon => unsafe { neon::scale_mul(y, scale, w, x) },
+        _ => scalar::scale_mul(y, scale, w, x),
+    }
+}
+
+// y[i] *= silu(x[i])
+#[inline]
+pub fn swiglu(y: &mut [f32], x: &[f32]) {
+    assert_eq!(y.len(), x.len());
+    match isa() {
+        #[cfg(target_arch = "x86_64")]
+        Isa::Avx2Fma => unsafe { avx2::swiglu(y, x) },
+        #[cfg(target_arch = "aarch64")]
+        Isa::Neon => unsafe { neon::swiglu(y, x) },
+        _ => scalar::swiglu(y, x),
+    }
+}
+
+pub mod scalar {
+    pub fn dot(a: &[f32], b: &[f32]) -> f32 {
+        a.iter().zip(b).map(|(&x, &y)| x * y).sum()
+    }
+
+    pub fn dot4(x: &[f32], w: [&[f32]; 4]) -> [f32; 4] {
+        w.map(|w| dot(x, w))
+    }
+
+    pub fn axpy(y: &mut [f32], alpha: f32, x: &[f32]) {
+        y.iter_mut().zip(x).for_each(|(y, &x)| *y += alpha * x);
+    }
+
+    pub fn scale_mul(y: &mut [f32], scale: f32, w: &[f32], x: &[f32]) {
+        for ((y, &w), &x) in y.iter_mut().zip(w).zip(x) {
+            *y = scale * w * x;
+        }
+    }
+
+    pub fn swiglu(y: &mut [f32], x: &[f32]) {
+        for (y, &x) in y.iter_mut().zip(x) {
+            *y *= x / (1.0 + (-x).exp());
+        }
+    }
+}
+
+// Constants of the Cephes single precision exp approximation
+#[allow(unused)]
+mod exp_consts {
+    pub const EXP_HI: f32 = 88.376_26;
+    pub const EXP_LO: f32 = -88.376_26;
+    pub const LOG2E: f32 = std::f32::consts::LOG2_E;
+    pub const LN2_HI: f32 = 0.693_359_4;
+    pub const LN2_LO: f32 = -2.121_944_4e-4;
+    pub const P0: f32 = 1.987_569_1e-4;
+    pub const P1: f32 = 1.398_199_9e-3;
+    pub const P2: f32 = 8.333_452e-3;
+    pub const P3: f32 = 4.166_579_6e-2;
+    pub const P4: f32 = 1.666_666_5e-1;
+    pub const P5: f32 = 0.5;
+}
+
+#[cfg(target_arch = "x86_64")]
+pub mod avx2 {
+    use super::exp_consts::*;
+    use std::arch::x86_64::*;
+
+    #[inline]
+    #[target_feature(enable = "avx2,fma")]
+    unsafe fn hsum(v: __m256) -> f32 {
+        let lo = _mm256_castps256_ps128(v);
+        let hi = _mm256_extractf128_ps(v, 1);
+        let s = _mm_add_ps(lo, hi);
+        let s = _mm_add_ps(s, _mm_movehl_ps(s, s));
+        let s = _mm_add_ss(s, _mm_movehdup_ps(s));
+        _mm_cvtss_f32(s)
+    }
+
+    #[inline]
+    #[target_feature(enable = "avx2,fma")]
+    unsafe fn exp(x: __m256) -> __m256 {
+        let x = _mm256_min_ps(
+            _mm256_max_ps(x, _mm256_set1_ps(EXP_LO)),
+            _mm256_set1_ps(EXP_HI),
+        );
+        // x = n * ln2 + r, |r| <= ln2 / 2
+        let n = _mm256_floor_ps(_mm256_fmadd_ps(
+            x,
+            _mm256_set1_ps(LOG2E),
+            _mm256_set1_ps(0.5),
+        ));
+        let r = _mm256_fnmadd_ps(n, _mm256_set1_ps(LN2_HI), x);
+        let r = _mm256_fnmadd_ps(n, _mm256_set1_ps(LN2_LO), r);
+        let mut p = _mm256_set1_ps(P0);
+        for c in [P1, P2, P3, P4, P5] {
+            p = _mm256_fmadd_ps(p, r, _mm256_set1_ps(c));
+        }
+        let p = _mm256_fmadd_ps(
+            p,
+            _mm256_mul_ps(r, r),
+            _mm256_add_ps(r, _mm256_set1_ps(1.0)),
+        );
+        // 2^n built directly in the exponent bits
+        let pow2n = _mm256_slli_epi32(
+            _mm256_add_epi32(_mm256_cvtps_epi32(n), _mm256_set1_epi32(127)),
+            23,
+        );
+        _mm256_mul_ps(p, _mm256_castsi256_ps(pow2n))
+    }
+
+    #[target_feature(enable = "avx2,fma")]
+    pub unsafe fn dot(a: &[f32], b: &[f32]) -> f32 {
+        let n = a.len().min(b.len());
+        let (pa, pb) = (a.as_ptr(), b.as_ptr());
+        let mut acc = [_mm256_setzero_ps(); 4];
+        let mut i = 0;
+        while i + 32 <= n {
+            for (j, acc) in acc.iter_mut().enumerate() {
+                let x = _mm256_loadu_ps(pa.add(i + j * 8));
+                let y = _mm256_loadu_ps(pb.add(i + j * 8));
+                *acc = _mm256_fmadd_ps(x, y, *acc);
+            }
+            i += 32;
+        }
+        while i + 8 <= n {
+            acc[0] = _mm256_fmadd_ps(
+                _mm256_loadu_ps(pa.add(i)),
+                _mm256_loadu_ps(pb.add(i)),
+                acc[0],
+            );
+            i += 8;
+        }
+        let sum = _mm256_add_ps(_mm256_add_ps(acc[0], acc[1]), _mm256_add_ps(acc[2], acc[3]));
+        let mut sum = hsum(sum);
+        for k in i..n {
+            sum += a[k] * b[k];
+        }
+        sum
+    }
+
+    #[target_feature(enable = "avx2,fma")]
+    pub unsafe fn dot4(x: &[f32], w: [&[f32]; 4]) -> [f32; 4] {
+        let n = x.len();
+        let px = x.as_ptr();
+        let pw = w.map(|w| w.as_ptr());
+        let mut acc = [[_mm256_setzero_ps(); 2]; 4];
+        let mut i = 0;
+        while i + 16 <= n {
+            let x0 = _mm256_loadu_ps(px.add(i));
+            let x1 = _mm256_loadu_ps(px.add(i + 8));
+            for (acc, pw) in acc.iter_mut().zip(pw) {
+                acc[0] = _mm256_fmadd_ps(x0, _mm256_loadu_ps(pw.add(i)), acc[0]);
+                acc[1] = _mm256_fmadd_ps(x1, _mm256_loadu_ps(pw.add(i + 8)), acc[1]);
+            }
+            i += 16;
+        }
+        let mut out = [0.; 4];
+        for r in 0..4 {
+            out[r] = hsum(_mm256_add_ps(acc[r][0], acc[r][1]));
+            for k in i..n {
+                out[r] += x[k] * w[r][k];
+            }
+        }
+        out
+    }
+
+    #[target_feature(enable = "avx2,fma")]
+    pub unsafe fn axpy(y: &mut [f32], alpha: f32, x: &[f32]) {
+        let n = y.len().min(x.len());
+        let (py, px) = (y.as_mut_ptr(), x.as_ptr());
+        let va = _mm256_set1_ps(alpha);
+        let mut i = 0;
+        while i + 8 <= n {
+            let v = _mm256_fmadd_ps(va, _mm256_loadu_ps(px.add(i)), _mm256_loadu_ps(py.add(i)));
+            _mm256_storeu_ps(py.add(i), v);
+            i += 8;
+        }
+        for k in i..n {
+            y[k] += alpha * x[k];
+        }
+    }
+
+    #[target_feature(enable = "avx2,fma")]
+    pub unsafe fn scale_mul(y: &mut [f32], scale: f32, w: &[f32], x: &[f32]) {
+        let n = y.len().min(w.len()).min(x.len());
+        let (py, pw, px) = (y.as_mut_ptr(), w.as_ptr(), x.as_ptr());
+        let vs = _mm256_set1_ps(scale);
+        let mut i = 0;
+        while i + 8 <= n {
+            let v = _mm256_mul_ps(_mm256_loadu_ps(pw.add(i)), _mm256_loadu_ps(px.add(i)));
+            _mm256_storeu_ps(py.add(i), _mm256_mul_ps(vs, v));
+            i += 8;
+        }
+        for k in i..n {
+            y[k] = scale * w[k] * x[k];
+        }
+    }
+
+    #[target_feature(enable = "avx2,fma")]
+    pub unsafe fn swiglu(y: &mut [f32], x: &[f32]) {
+        let n = y.len().min(x.len());
+        let (py, px) = (y.as_mut_ptr(), x.as_ptr());
+        let one = _mm256_set1_ps(1.0);
+        let mut i = 0;
+        while i + 8 <= n {
+            let vx = _mm256_loadu_ps(px.add(i));
+            let e = exp(_mm256_sub_ps(_mm256_setzero_ps(), vx));
+            let silu = _mm256_div_ps(vx, _mm256_add_ps(one, e));
+            _mm256_storeu_ps(py.add(i), _mm256_mul_ps(_mm256_loadu_ps(py.add(i)), silu));
+            i += 8;
+        }
+        super::scalar::swiglu(&mut y[i..n], &x[i..n]);
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+pub mod neon {
+    use super::exp_consts::*;
+    use std::arch::aarch64::*;
+
+    #[inline]
+    #[target_feature(enable = "neon")]
+    unsafe fn exp(x: float32x4_t) -> float32x4_t {
+        let x = vminq_f32(vmaxq_f32(x, vdupq_n_f32(EXP_LO)), vdupq_n_f32(EXP_HI));
+        // x = n * ln2 + r, |r| <= ln2 / 2
+        let n = vrndmq_f32(vfmaq_f32(vdupq_n_f32(0.5), x, vdupq_n_f32(LOG2E)));
+        let r = vfmsq_f32(x, n, vdupq_n_f32(LN2_HI));
+        let r = vfmsq_f32(r, n, vdupq_n_f32(LN2_LO));
+        let mut p = vdupq_n_f32(P0);
+        for c in [P1, P2, P3, P4, P5] {
+            p = vfmaq_f32(vdupq_n_f32(c), p, r);
+        }
+        let p = vfmaq_f32(vaddq_f32(r, vdupq_n_f32(1.0)), p, vmulq_f32(r, r));
+        // 2^n built directly in the exponent bits
+        let pow2n = vshlq_n_s32::<23>(vaddq_s32(vcvtq_s32_f32(n), vdupq_n_s32(127)));
+        vmulq_f32(p, vreinterpretq_f32_s32(pow2n))
+    }
+
+    #[target_feature(enable = "neon")]
+    pub unsafe fn dot(a: &[f32], b: &[f32]) -> f32 {
+        let n = a.len().min(b.len());
+        let (pa, pb) = (a.as_ptr(), b.as_ptr());
+        let mut acc = [vdupq_n_f32(0.0); 4];
+        let mut i = 0;
+        while i + 16 <= n {
+            for (j, acc) in acc.iter_mut().enumerate() {
+                *acc = vfmaq_f32(
+                    *acc,
+                    vld1q_f32(pa.add(i + j * 4)),
+                    vld1q_f32(pb.add(i + j * 4)),
+                );
+            }
+            i += 16;
+        }
+        while i + 4 <= n {
+            acc[0] = vfmaq_f32(acc[0], vld1q_f32(pa.add(i)), vld1q_f32(pb.add(i)));
+            i += 4;
+        }
+        let sum = vaddq_f32(vaddq_f32(acc[0], acc[1]), vaddq_f32(acc[2], acc[3]));
+        let mut sum = vaddvq_f32(sum);
+        for k in i..n {
+            sum += a[k] * b[k];
+        }
+        sum
+    }
+
+    #[target_feature(enable = "neon")]
+    pub unsafe fn dot4(x: &[f32], w: [&[f32]; 4]) -> [f32; 4] {
+        let n = x.len();
+        let px = x.as_ptr();
+        let pw = w.map(|w| w.as_ptr());
+        let mut acc = [[vdupq_n_f32(0.); 2]; 4];
+        let mut i = 0;
+        while i + 8 <= n {
+            let x0 = vld1q_f32(px.add(i));
+            let x1 = vld1q_f32(px.add(i + 4));
+            for (acc, pw) in acc.iter_mut().zip(pw) {
+                acc[0] = vfmaq_f32(acc[0], x0, vld1q_f32(pw.add(i)));
+                acc[1] = vfmaq_f32(acc[1], x1, vld1q_f32(pw.add(i + 4)));
+            }
+            i += 8;
+        }
+        let mut out = [0.; 4];
+        for r in 0..4 {
+            out[r] = vaddvq_f32(vaddq_f32(acc[r][0], acc[r][1]));
+            for k in i..n {
+                out[r] += x[k] * w[r][k];
+            }
+        }
+        out
+    }
+
+    #[target_feature(enable = "neon")]
+    pub unsafe fn axpy(y: &mut [f32], alpha: f32, x: &[f32]) {
+        let n = y.len().min(x.len());
+        let (py, px) = (y.as_mut_ptr(), x.as_ptr());
+        let va = vdupq_n_f32(alpha);
+        let mut i = 0;
+        while i + 4 <= n {
+            vst1q_f32(
+                py.add(i),
+                vfmaq_f32(vld1q_f32(py.add(i)), va, vld1q_f32(px.add(i))),
+            );
+            i += 4;
+        }
+        for k in i..n {
+            y[k] += alpha * x[k];
+        }
+    }
+
+    #[target_feature(enable = "neon")]
+    pub unsafe fn scale_mul(y: &mut [f32], scale: f32, w: &[f32], x: &[f32]) {
+        let n = y.len().min(w.len()).min(x.len());
+        let (py, pw, px) = (y.as_mut_ptr(), w.as_ptr(), x.as_ptr());
+        let mut i = 0;
+        while i + 4 <= n {
+            let v = vmulq_f32(vld1q_f32(pw.add(i)), vld1q_f32(px.add(i)));
+            vst1q_f32(py.add(i), vmulq_n_f32(v, scale));
+            i += 4;
+        }
+        for k in i..n {
+            y[k] = scale * w[k] * x[k];
+        }
+    }
+
+    #[target_feature(enable = "neon")]
+    pub unsafe fn swiglu(y: &mut [f32], x: &[f32]) {
+        let n = y.len().min(x.len());
+        let (py, px) = (y.as_mut_ptr(), x.as_ptr());
+        let one = vdupq_n_f32(1.0);
+        let mut i = 0;
+        while i + 4 <= n {
+            let vx = vld1q_f32(px.add(i));
+            let silu = vdivq_f32(vx, vaddq_f32(one, exp(vnegq_f32(vx))));
+            vst1q_f32(py.add(i), vmulq_f32(vld1q_f32(py.add(i)), silu));
+            i += 4;
+        }
+        super::scalar::swiglu(&mut y[i..n], &x[i..n]);
+    }
+}
+
+#[cfg(test)]
+fn test_vec(len: usize, seed: usize) -> Vec<f32> {
+    (0..len)
+        .map(|i| ((i * 7 + seed * 13) % 23) as f32 * 0.37 - 4.0)
+        .collect()
+}
+
+#[cfg(test)]
+fn assert_close(x: &[f32], y: &[f32]) {
+    assert_eq!(x.len(), y.len());
+    for (a, b) in x.iter().zip(y) {
+        assert!((a - b).abs() <= 1e-4 * (1.0 + b.abs()), "{a} != {b}");
+    }
+}
+
+#[cfg(test)]
+const TEST_LENGTHS: [usize; 8] = [0, 1, 3, 8, 15, 32, 67, 300];
+
+#[test]
+fn test_simd_dot() {
+    for len in TEST_LENGTHS {
+        let (a, b) = (test_vec(len, 1), test_vec(len, 2));
+        let expected = scalar::dot(&a, &b);
+        #[cfg(target_arch = "x86_64")]
+        if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
+            assert_close(&[unsafe { avx2::dot(&a, &b) }], &[expected]);
+        }
+        #[cfg(target_arch = "aarch64")]
+        if std::arch::is_aarch64_feature_detected!("neon") {
+            assert_close(&[unsafe { neon::dot(&a, &b) }], &[expected]);
+        }
+        assert_close(&[dot(&a, &b)], &[expected]);
+    }
+}
+
+#[test]
+fn test_simd_dot4() {
+    for len in TEST_LENGTHS {
+        let x = test_vec(len, 1);
+        let rows = [2, 3, 4, 5].map(|seed| test_vec(len, seed));
+        let w = [&rows[0][..], &rows[1], &rows[2], &rows[3]];
+        let expected = scalar::dot4(&x, w);
+        #[cfg(target_arch = "x86_64")]
+        if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
+            assert_close(&unsafe { avx2::dot4(&x, w) }, &expected);
+        }
+        #[cfg(target_arch = "aarch64")]
+        if std::arch::is_aarch64_feature_detected!("neon") {
+            assert_close(&unsafe { neon::dot4(&x, w) }, &expected);
+        }
+        assert_close(&dot4(&x, w), &expected);
+    }
+}
+
+#[test]
+fn test_simd_axpy() {
+    for len in TEST_LENGTHS {
+        let (y, x) = (test_vec(len, 3), test_vec(len, 4));
+        let mut expected = y.clone();
+        scalar::axpy(&mut expected, 0.3, &x);
+        #[cfg(target_arch = "x86_64")]
+        if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
+            let mut out = y.clone();
+            unsafe { avx2::axpy(&mut out, 0.3, &x) };
+            assert_close(&out, &expected);
+        }
+        #[cfg(target_arch = "aarch64")]
+        if std::arch::is_aarch64_feature_detected!("neon") {
+            let mut out = y.clone();
+            unsafe { neon::axpy(&mut out, 0.3, &x) };
+            assert_close(&out, &expected);
+        }
+        let mut out = y;
+        axpy(&mut out, 0.3, &x);
+        assert_close(&out, &expected);
+    }
+}
+
+#[test]
+fn test_simd_scale_mul() {
+    for len in TEST_LENGTHS {
+        let (w, x) = (test_vec(len, 5), test_vec(len, 6));
+        let mut expected = vec![0.; len];
+        scalar::scale_mul(&mut expected, 0.7, &w, &x);
+        #[cfg(target_arch = "x86_64")]
+        if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
+            let mut out = vec![0.; len];
+            unsafe { avx2::scale_mul(&mut out, 0.7, &w, &x) };
+            assert_close(&out, &expected);
+        }
+        #[cfg(target_arch = "aarch64")]
+        if std::arch::is_aarch64_feature_detected!("neon") {
+            let mut out = vec![0.; len];
+            unsafe { neon::scale_mul(&mut out, 0.7, &w, &x) };
+            assert_close(&out, &expected);
+        }
+        let mut out = vec![0.; len];
+        scale_mul(&mut out, 0.7, &w, &x);
+        assert_close(&out, &expected);
+    }
+}
+
+#[test]
+fn test_simd_swiglu() {
+    let mut lengths = TEST_LENGTHS.to_vec();
+    lengths.push(16);
+    for len in lengths {
+        let y = test_vec(len, 7);
+        // include values that saturate the exp approximation
+        let x = (0..len)
+            .map(|i| match i % 8 {
+                0 => 95.0,
+                1 => -95.0,
+                _ => (i as f32 - len as f32 / 2.0) * 0.25,
+            })
+            .collect::<Vec<_>>();
+        let mut expected = y.clone();
+        scalar::swiglu(&mut expected, &x);
+        #[cfg(target_arch = "x86_64")]
+        if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
+            let mut out = y.clone();
+            unsafe { avx2::swiglu(&mut out, &x) };
+            assert_close(&out, &expected);
+        }
+        #[cfg(target_arch = "aarch64")]
+        if std::arch::is_aarch64_feature_detected!("neon") {
+            let mut out = y.clone();
+            unsafe { neon::swiglu(&mut out, &x) };
+            assert_close(&out, &expected);
+        }
+        let mut out = y;
+        swiglu(&mut out, &x);
+        assert_close(&out, &expected);
+    }
+}
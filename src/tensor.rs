@@ -1,33 +1,105 @@
-use std::{slice, sync::Arc, vec};
+use memmap2::Mmap;
+use std::{ops::Deref, slice, sync::Arc, vec};
+
+// The elements behind a tensor: either owned, or borrowed straight from a memory-mapped
+// checkpoint that the Arc keeps alive
+enum Storage<T> {
+    Owned(Box<[T]>),
+    Mapped {
+        _map: Arc<Mmap>,
+        ptr: *const T,
+        len: usize,
+    },
+}
+
+// The mapping is never written through and outlives ptr, so it can be shared like a Box<[T]>
+unsafe impl<T: Sync> Send for Storage<T> {}
+unsafe impl<T: Sync> Sync for Storage<T> {}
+
+impl<T> Deref for Storage<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        match self {
+            Storage::Owned(data) => data,
+            Storage::Mapped { ptr, len, .. } => unsafe { slice::from_raw_parts(*ptr, *len) },
+        }
+    }
+}
+
 pub struct Tensor<T> {
-    data: Arc<Box<[T]>>,
+    data: Arc<Storage<T>>,
     shape: Vec<usize>,
     offset: usize,
     length: usize,
 }
 
+// A clone is another view of the same storage, like `slice`
+impl<T> Clone for Tensor<T> {
+    fn clone(&self) -> Self {
+        Tensor {
+            data: self.data.clone(),
+            shape: self.shape.clone(),
+            offset: self.offset,
+            length: self.length,
+        }
+    }
+}
+
 impl<T: Copy + Clone + Default> Tensor<T> {
+    #[allow(clippy::ptr_arg)]
     pub fn new(data: Vec<T>, shape: &Vec<usize>) -> Self {
         let length = data.len();
         Tensor {
-            data: Arc::new(data.into_boxed_slice().try_into().unwrap()),
+            data: Arc::new(Storage::Owned(data.into_boxed_slice())),
             shape: shape.clone(),
             offset: 0,
-            length: length,
+            length,
         }
     }
 
+    #[allow(clippy::ptr_arg)]
     pub fn default(shape: &Vec<usize>) -> Self {
         let length = shape.iter().product();
         let data = vec![T::default(); length];
         Self::new(data, shape)
     }
 
+    // Borrow shape.product() elements starting at byte `offset` of the mapping without copying.
+    // Returns None when that range is out of bounds or not aligned for T.
+    // Safety: every bit pattern of the mapped bytes must be a valid T.
+    #[allow(clippy::ptr_arg)]
+    pub unsafe fn from_mapping(map: Arc<Mmap>, offset: usize, shape: &Vec<usize>) -> Option<Self> {
+        let length: usize = shape.iter().product();
+        let bytes = map.get(offset..offset.checked_add(length * size_of::<T>())?)?;
+        let ptr = bytes.as_ptr() as *const T;
+        if !ptr.is_aligned() {
+            return None;
+        }
+        Some(Tensor {
+            data: Arc::new(Storage::Mapped {
+                _map: map,
+                ptr,
+                len: length,
+            }),
+            shape: shape.clone(),
+            offset: 0,
+            length,
+        })
+    }
+
+    #[allow(unused)]
+    pub fn is_mapped(&self) -> bool {
+        matches!(*self.data, Storage::Mapped { .. })
+    }
+
     pub fn data(&self) -> &[T] {
         &self.data[self.offset..][..self.length]
     }
 
     pub unsafe fn data_mut(&mut self) -> &mut [T] {
+        // 映射的只读内存不可写
+        assert!(!self.is_mapped(), "Cannot write to a memory-mapped tensor");
         let ptr = self.data.as_ptr().add(self.offset) as *mut T;
         slice::from_raw_parts_mut(ptr, self.length)
     }
@@ -51,6 +123,7 @@ impl<T: Copy + Clone + Default> Tensor<T> {
         self
     }
 
+    #[allow(clippy::ptr_arg)]
     pub fn slice(&self, start: usize, shape: &Vec<usize>) -> Self {
         let new_length: usize = shape.iter().product();
         assert!(self.offset + start + new_length <= self.length);
@@ -61,8 +134,6 @@ impl<T: Copy + Clone + Default> Tensor<T> {
             length: new_length,
         }
     }
-
-
 }
 
 // Some helper functions for testing and debugging
@@ -74,12 +145,15 @@ impl Tensor<f32> {
         }
         let a = self.data();
         let b = other.data();
-        
-        return a.iter().zip(b).all(|(x, y)| float_eq(x, y, rel));
+
+        a.iter().zip(b).all(|(x, y)| float_eq(x, y, rel))
     }
     #[allow(unused)]
-    pub fn print(&self){
-        println!("shpae: {:?}, offset: {}, length: {}", self.shape, self.offset, self.length);
+    pub fn print(&self) {
+        println!(
+            "shpae: {:?}, offset: {}, length: {}",
+            self.shape, self.offset, self.length
+        );
         let dim = self.shape()[self.shape().len() - 1];
         let batch = self.length / dim;
         for i in 0..batch {
@@ -0,0 +1,111 @@
+// Weight matrices as seen by the matmul kernels.
+// The kernels only ever need a (rows, cols) matrix one row (or a KC-wide piece of a row)
+// at a time, so dense tensors of any `WeightType` and blockwise quantized tensors share
+// the same GEMM / GEMV code.
+use crate::dtype::WeightType;
+use crate::quant::{BlockQ4_0, BlockQ4_1, BlockQ8_0, QTensor, QuantType};
+use crate::tensor::Tensor;
+
+pub trait WeightMatrix: Sync {
+    fn rows(&self) -> usize;
+
+    fn cols(&self) -> usize;
+
+    // dst = row[col0..col0 + dst.len()] widened to f32
+    fn row_to_f32(&self, row: usize, col0: usize, dst: &mut [f32]);
+
+    // dot(x, row)
+    fn row_dot(&self, row: usize, x: &[f32]) -> f32;
+
+    // [dot(x, row), .., dot(x, row + 3)]
+    fn row_dot4(&self, row: usize, x: &[f32]) -> [f32; 4] {
+        [0, 1, 2, 3].map(|i| self.row_dot(row + i, x))
+    }
+}
+
+impl<T: WeightType> WeightMatrix for Tensor<T> {
+    fn rows(&self) -> usize {
+        self.shape()[0]
+    }
+
+    fn cols(&self) -> usize {
+        self.shape()[1]
+    }
+
+    fn row_to_f32(&self, row: usize, col0: usize, dst: &mut [f32]) {
+        let k = self.cols();
+        T::to_f32_slice(&self.data()[row * k + col0..][..dst.len()], dst);
+    }
+
+    fn row_dot(&self, row: usize, x: &[f32]) -> f32 {
+        let k = self.cols();
+        T::dot(x, &self.data()[row * k..][..k])
+    }
+
+    fn row_dot4(&self, row: usize, x: &[f32]) -> [f32; 4] {
+        let k = self.cols();
+        let r = |i: usize| &self.data()[(row + i) * k..][..k];
+        T::dot4(x, [r(0), r(1), r(2), r(3)])
+    }
+}
+
+// A weight matrix, kept either in the model's storage type or quantized; clones share storage
+#[derive(Clone)]
+pub enum Weight<T> {
+    Dense(Tensor<T>),
+    Q8_0(QTensor<BlockQ8_0>),
+    Q4_0(QTensor<BlockQ4_0>),
+    Q4_1(QTensor<BlockQ4_1>),
+}
+
+impl<T> Weight<T> {
+    #[allow(unused)]
+    pub fn as_dense(&self) -> Option<&Tensor<T>> {
+        match self {
+            Weight::Dense(t) => Some(t),
+            _ => None,
+        }
+    }
+}
+
+impl<T: WeightType> Weight<T> {
+    pub fn quantize(t: &Tensor<f32>, quant: QuantType) -> Self {
+        let (rows, cols) = (t.rows(), t.cols());
+        match quant {
+            QuantType::Q8_0 => Weight::Q8_0(QTensor::quantize(t.data(), rows, cols)),
+            QuantType::Q4_0 => Weight::Q4_0(QTensor::quantize(t.data(), rows, cols)),
+            QuantType::Q4_1 => Weight::Q4_1(QTensor::quantize(t.data(), rows, cols)),
+        }
+    }
+
+    fn matrix(&self) -> &dyn WeightMatrix {
+        match self {
+            Weight::Dense(t) => t,
+            Weight::Q8_0(q) => q,
+            Weight::Q4_0(q) => q,
+            Weight::Q4_1(q) => q,
+        }
+    }
+}
+
+impl<T: WeightType> WeightMatrix for Weight<T> {
+    fn rows(&self) -> usize {
+        self.matrix().rows()
+    }
+
+    fn cols(&self) -> usize {
+        self.matrix().cols()
+    }
+
+    fn row_to_f32(&self, row: usize, col0: usize, dst: &mut [f32]) {
+        self.matrix().row_to_f32(row, col0, dst)
+    }
+
+    fn row_dot(&self, row: usize, x: &[f32]) -> f32 {
+        self.matrix().row_dot(row, x)
+    }
+
+    fn row_dot4(&self, row: usize, x: &[f32]) -> [f32; 4] {
+        self.matrix().row_dot4(row, x)
+    }
+}